@@ -0,0 +1,129 @@
+//! Benchmarks of the routing hot path: parsing a switch packet, switching
+//! it, and building packets, including the response to a `gp` query.
+//!
+//! Run them with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate fcp_cryptoauth;
+extern crate fcp_switching;
+
+use std::collections::HashMap;
+
+use criterion::{Criterion, BatchSize, black_box};
+
+use fcp_cryptoauth::wrapper::{Wrapper, Credentials, gen_keypair};
+
+use fcp_switching::operation::label_from_u64;
+use fcp_switching::switch_packet::{SwitchPacket, Payload};
+use fcp_switching::data_packet::DataPacket;
+use fcp_switching::data_packet::Payload as DataPayload;
+use fcp_switching::route_packet::RoutePacket;
+use fcp_switching::switch_core::{SwitchCore, SwitchConfig};
+
+/// Size of the CryptoAuth data carried by the benchmarked data packet.
+const DATA_LENGTH: usize = 1024;
+
+/// Number of routers connected to the switch answering the benchmarked
+/// `gp` queries; too many to announce them all in a response, so it has
+/// to be trimmed to the MTU.
+const NB_PEERS: usize = 50;
+
+/// Label of a data packet forwarded through interface `0b011`, then
+/// `0b101`.
+fn data_label() -> [u8; 8] {
+    label_from_u64(0b0001_101_011)
+}
+
+fn data_packet() -> SwitchPacket {
+    SwitchPacket::new(&data_label(), Payload::CryptoAuthData(1234, vec![42; DATA_LENGTH]))
+}
+
+/// Returns a switch with `NB_PEERS` routers connected to it, each of
+/// them opening its session with a `pn` query.
+fn switch_with_peers() -> SwitchCore {
+    fcp_cryptoauth::init();
+    let (my_pk, my_sk) = gen_keypair();
+    let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+    let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+    let ping = DataPacket::new(1, &DataPayload::RoutePacket(RoutePacket::ping(18, b"pn txid".to_vec())));
+    for _ in 0..NB_PEERS {
+        let (router_pk, router_sk) = gen_keypair();
+        let mut router = Wrapper::new_outgoing_connection(router_pk, router_sk, my_pk, Credentials::None, None, (), Some(1234));
+        let hello = router.wrap_message_immediately(&ping.raw).remove(0);
+        let packet = SwitchPacket::new(&label_from_u64(0b001), Payload::CryptoAuthHandshake(hello));
+        switch.handle_messages(0b011, vec![packet.raw]);
+    }
+    assert_eq!(switch.sessions().count(), NB_PEERS);
+    switch
+}
+
+fn getpeers_query() -> RoutePacket {
+    RoutePacket::get_peers(18, b"gp txid".to_vec(), vec![0; 16])
+}
+
+/// Builds the response of the switch to a `gp` query from a router it
+/// has no session with, and encodes it as a data packet.
+fn getpeers_response(switch: &SwitchCore, query: &RoutePacket) -> Vec<u8> {
+    let response = switch.getpeers_response(query, 0).unwrap();
+    DataPacket::new(1, &DataPayload::RoutePacket(response)).raw
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let raw = data_packet().raw;
+    c.bench_function("parse", |b| b.iter_batched(
+            || raw.clone(),
+            |raw| SwitchPacket::parse(raw).unwrap(),
+            BatchSize::SmallInput));
+}
+
+fn bench_switch(c: &mut Criterion) {
+    let raw = data_packet().raw;
+    c.bench_function("switch", |b| b.iter_batched(
+            || SwitchPacket::parse(raw.clone()).unwrap(),
+            |mut packet| {
+                packet.switch(3, &0b100).unwrap();
+                packet
+            },
+            BatchSize::SmallInput));
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let data = vec![42; DATA_LENGTH];
+    c.bench_function("serialize", |b| b.iter_batched(
+            || data.clone(),
+            |data| SwitchPacket::new(&data_label(), Payload::CryptoAuthData(1234, data)).raw,
+            BatchSize::SmallInput));
+}
+
+fn bench_parse_switch_serialize(c: &mut Criterion) {
+    let raw = data_packet().raw;
+    c.bench_function("parse_switch_serialize", |b| b.iter_batched(
+            || raw.clone(),
+            |raw| {
+                let mut packet = SwitchPacket::parse(raw).unwrap();
+                packet.switch(3, &0b100).unwrap();
+                packet.raw
+            },
+            BatchSize::SmallInput));
+}
+
+fn bench_getpeers_response(c: &mut Criterion) {
+    let switch = switch_with_peers();
+    let query = getpeers_query();
+    c.bench_function("getpeers_response", |b| b.iter(|| getpeers_response(&switch, black_box(&query))));
+}
+
+fn bench_decode_getpeers_response(c: &mut Criterion) {
+    let raw = getpeers_response(&switch_with_peers(), &getpeers_query());
+    assert!(RoutePacket::decode(&raw[4..]).unwrap().has_more()); // Trimmed
+    let route_packet = raw[4..].to_vec(); // Strip the data header
+    c.bench_function("decode_getpeers_response", |b| b.iter(|| {
+        RoutePacket::decode(black_box(&route_packet)).unwrap().read_nodes().unwrap()
+    }));
+}
+
+criterion_group!(benches,
+        bench_parse, bench_switch, bench_serialize, bench_parse_switch_serialize,
+        bench_getpeers_response, bench_decode_getpeers_response);
+criterion_main!(benches);
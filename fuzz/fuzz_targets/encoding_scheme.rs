@@ -0,0 +1,19 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate fcp_switching;
+
+use std::iter::FromIterator;
+
+use fcp_switching::encoding_scheme::EncodingScheme;
+
+fuzz_target!(|data: &[u8]| {
+    // Iterating over an arbitrary scheme must never panic.
+    let forms = EncodingScheme::new(data.to_vec()).into_iter().collect::<Vec<_>>();
+
+    if let Ok(scheme) = EncodingScheme::deserialize(data) {
+        // Only sane schemes are accepted, and they are kept as they are.
+        assert!(scheme.is_sane());
+        assert_eq!(scheme.bytes(), &data.to_vec());
+        assert_eq!(EncodingScheme::from_iter(forms.iter()), scheme);
+    }
+});
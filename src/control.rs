@@ -1,7 +1,15 @@
 //! Switch control packets.
 
+use std::time::Duration;
+use std::fmt;
+use std::error;
+
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
+use rand;
+use rand::Rng;
+
+use PROTOCOL_VERSION;
 
 #[derive(Clone)]
 #[derive(Debug)]
@@ -43,7 +51,7 @@ impl ErrorType {
             5 => Some(ErrorType::UndersizeMessage),
             6 => Some(ErrorType::Authentication),
             7 => Some(ErrorType::Invalid),
-            8 => Some(ErrorType::UndersizeMessage),
+            8 => Some(ErrorType::Undeliverable),
             9 => Some(ErrorType::LoopRoute),
             10 => Some(ErrorType::ReturnPathInvalid),
             _ => None,
@@ -71,6 +79,11 @@ const PONG_MAGIC: u32 = 0x9d74e35b;
 const KEYPING_MAGIC: u32 = 0x01234567;
 const KEYPONG_MAGIC: u32 = 0x89abcdef;
 
+/// A switch control packet.
+///
+/// The opaque data of pings and pongs (and their key variants) may be of
+/// any length; the only limit is that the switch packet carrying them
+/// must fit in the MTU of the link.
 #[derive(Clone)]
 #[derive(Debug)]
 #[derive(Eq)]
@@ -83,16 +96,58 @@ pub enum ControlPacket {
     KeyPong { version: u32, opaque_data: Vec<u8>, key: Vec<u8> },
 }
 
+/// An error returned by `ControlPacket::decode`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ControlError {
+    /// The packet is too short for its type; the argument is its length.
+    Truncated(usize),
+    /// The type of the packet is not known.
+    UnknownType(u16),
+    /// The type of error of an Error packet is not known.
+    UnknownErrorType(u32),
+    /// The magic number does not match the type of the packet.
+    BadMagic(u32),
+    /// The checksum does not match the content of the packet.
+    BadChecksum,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ControlError::Truncated(length) => write!(f, "truncated ({} bytes)", length),
+            ControlError::UnknownType(type_) => write!(f, "unknown type {}", type_),
+            ControlError::UnknownErrorType(type_) => write!(f, "unknown error type {}", type_),
+            ControlError::BadMagic(magic) => write!(f, "bad magic {:#x}", magic),
+            ControlError::BadChecksum => write!(f, "bad checksum"),
+        }
+    }
+}
+
+impl error::Error for ControlError {}
+
 impl ControlPacket {
     /// Returns a ControlPacket from its raw representation
-    pub fn decode(raw: &[u8]) -> Option<ControlPacket> {
-        let checksum = BigEndian::read_u16(&raw[0..2]); // TODO: check checksum
+    pub fn decode(raw: &[u8]) -> Result<ControlPacket, ControlError> {
+        if raw.len() < 4 {
+            return Err(ControlError::Truncated(raw.len()))
+        }
+        let checksum = BigEndian::read_u16(&raw[0..2]);
+        let mut unchecked = raw.to_vec();
+        BigEndian::write_u16(&mut unchecked[0..2], 0);
+        if checksum != ControlPacket::checksum(&unchecked) {
+            return Err(ControlError::BadChecksum)
+        }
         let type_ = BigEndian::read_u16(&raw[2..4]);
-        match type_ {
-            2 => if raw.len() < 8 { return None },
-            3 | 4 => if raw.len() < 18 { return None }, // PING or PONG
-            5 | 6 => if raw.len() < 52 { return None }, // KEYPING or KEYPONG
-            _ => return None // unknown type
+        // Opaque data can be of any length (even empty), so these
+        // only count the fixed fields.
+        let min_length = match type_ {
+            2 => 8,
+            3 | 4 => 12, // PING or PONG
+            5 | 6 => 56, // KEYPING or KEYPONG
+            _ => return Err(ControlError::UnknownType(type_)),
+        };
+        if raw.len() < min_length {
+            return Err(ControlError::Truncated(raw.len()))
         }
 
         let res = match type_ {
@@ -105,13 +160,15 @@ impl ControlPacket {
                             cause: raw[8..].to_vec(),
                         }
                     }
-                    None => return None,
+                    None => return Err(ControlError::UnknownErrorType(type_number)),
                 }
             },
             3 => {
                 let magic = BigEndian::read_u32(&raw[4..8]);
                 let version = BigEndian::read_u32(&raw[8..12]);
-                assert_eq!(magic, PING_MAGIC);
+                if magic != PING_MAGIC {
+                    return Err(ControlError::BadMagic(magic))
+                }
                 let opaque_data = raw[12..].to_vec();
                 ControlPacket::Ping {
                     version: version,
@@ -121,7 +178,9 @@ impl ControlPacket {
             4 => {
                 let magic = BigEndian::read_u32(&raw[4..8]);
                 let version = BigEndian::read_u32(&raw[8..12]);
-                assert_eq!(magic, PONG_MAGIC);
+                if magic != PONG_MAGIC {
+                    return Err(ControlError::BadMagic(magic))
+                }
                 let opaque_data = raw[12..].to_vec();
                 ControlPacket::Pong {
                     version: version,
@@ -131,7 +190,9 @@ impl ControlPacket {
             5 => {
                 let magic = BigEndian::read_u32(&raw[4..8]);
                 let version = BigEndian::read_u32(&raw[8..12]);
-                assert_eq!(magic, KEYPING_MAGIC);
+                if magic != KEYPING_MAGIC {
+                    return Err(ControlError::BadMagic(magic))
+                }
                 let opaque_data = raw[56..].to_vec();
                 ControlPacket::KeyPing {
                     version: version,
@@ -142,7 +203,9 @@ impl ControlPacket {
             6 => {
                 let magic = BigEndian::read_u32(&raw[4..8]);
                 let version = BigEndian::read_u32(&raw[8..12]);
-                assert_eq!(magic, KEYPONG_MAGIC);
+                if magic != KEYPONG_MAGIC {
+                    return Err(ControlError::BadMagic(magic))
+                }
                 let opaque_data = raw[56..].to_vec();
                 ControlPacket::KeyPong {
                     version: version,
@@ -152,7 +215,7 @@ impl ControlPacket {
             },
             _ => panic!("The impossible happened.")
         };
-        Some(res)
+        Ok(res)
     }
 
     fn checksum(raw: &[u8]) -> u16 {
@@ -222,6 +285,107 @@ impl ControlPacket {
         BigEndian::write_u16(&mut buf[0..2], checksum);
         buf
     }
+
+    /// Returns a ping whose opaque data is `now`, a timestamp in
+    /// milliseconds, so the latency can be computed from the pong with
+    /// `pong_latency`.
+    pub fn ping_with_timestamp(now: u64) -> ControlPacket {
+        ControlPacketBuilder::new().ping_with_timestamp(now)
+    }
+
+    /// Returns the time elapsed between the emission of the ping
+    /// this pong replies to, and `now` (in milliseconds, from the
+    /// same clock as the one passed to `ping_with_timestamp`).
+    ///
+    /// Returns None if this is not a pong, if its opaque data does not
+    /// contain a timestamp, or if the timestamp is after `now`.
+    pub fn pong_latency(&self, now: u64) -> Option<Duration> {
+        match *self {
+            ControlPacket::Pong { ref opaque_data, .. } if opaque_data.len() == 8 => {
+                let sent = BigEndian::read_u64(opaque_data);
+                now.checked_sub(sent).map(Duration::from_millis)
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the protocol version advertised by the emitter of this
+    /// packet, so a switch can refuse to talk to incompatible peers.
+    ///
+    /// As in cjdns, a pong carries the version of the node replying,
+    /// not an echo of the version of the ping; so a pong reports the
+    /// version of the peer which was pinged.
+    ///
+    /// Returns None for error packets, which carry no version.
+    pub fn peer_version(&self) -> Option<u32> {
+        match *self {
+            ControlPacket::Error { .. } => None,
+            ControlPacket::Ping { version, .. } |
+            ControlPacket::Pong { version, .. } |
+            ControlPacket::KeyPing { version, .. } |
+            ControlPacket::KeyPong { version, .. } => Some(version),
+        }
+    }
+}
+
+/// Helper for constructing control packets, with our protocol version
+/// and random opaque data.
+pub struct ControlPacketBuilder {
+    version: u32,
+}
+
+impl ControlPacketBuilder {
+    /// Returns a builder for packets with version `PROTOCOL_VERSION`.
+    pub fn new() -> ControlPacketBuilder {
+        ControlPacketBuilder { version: PROTOCOL_VERSION }
+    }
+
+    pub fn version(mut self, version: u32) -> ControlPacketBuilder {
+        self.version = version;
+        self
+    }
+
+    fn nonce() -> Vec<u8> {
+        let mut opaque_data = vec![0u8; 8];
+        rand::thread_rng().fill_bytes(&mut opaque_data);
+        opaque_data
+    }
+
+    /// Returns a ping, with a random nonce as opaque data.
+    pub fn ping(&self) -> ControlPacket {
+        ControlPacket::Ping { version: self.version, opaque_data: ControlPacketBuilder::nonce() }
+    }
+
+    /// Returns a ping with `now`, a timestamp in milliseconds, as
+    /// opaque data. See `ControlPacket::pong_latency`.
+    pub fn ping_with_timestamp(&self, now: u64) -> ControlPacket {
+        let mut opaque_data = vec![0u8; 8];
+        BigEndian::write_u64(&mut opaque_data, now);
+        ControlPacket::Ping { version: self.version, opaque_data: opaque_data }
+    }
+
+    /// Returns a pong replying to `ping`, echoing its opaque data.
+    /// Returns None if `ping` is not a ping.
+    pub fn pong_to(&self, ping: &ControlPacket) -> Option<ControlPacket> {
+        match *ping {
+            ControlPacket::Ping { ref opaque_data, .. } => {
+                Some(ControlPacket::Pong { version: self.version, opaque_data: opaque_data.clone() })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a key ping advertising `key`, with a random nonce as
+    /// opaque data.
+    pub fn key_ping(&self, key: Vec<u8>) -> ControlPacket {
+        ControlPacket::KeyPing { version: self.version, opaque_data: ControlPacketBuilder::nonce(), key: key }
+    }
+}
+
+impl Default for ControlPacketBuilder {
+    fn default() -> ControlPacketBuilder {
+        ControlPacketBuilder::new()
+    }
 }
 
 #[cfg(test)]
@@ -231,12 +395,22 @@ mod test {
     use super::*;
     use hex::FromHex;
 
+    /// Returns a copy of `raw` with its checksum updated, after it was
+    /// tampered with.
+    fn with_checksum(raw: &[u8]) -> Vec<u8> {
+        let mut raw = raw.to_vec();
+        BigEndian::write_u16(&mut raw[0..2], 0);
+        let checksum = ControlPacket::checksum(&raw);
+        BigEndian::write_u16(&mut raw[0..2], checksum);
+        raw
+    }
+
     #[test]
     fn ping() {
         let raw = Vec::from_hex("a2e5000309f91102000000124d160b1eee2929e12e19a3b1").unwrap();
         let msg = ControlPacket::Ping { version: 18, opaque_data: Vec::from_hex("4d160b1eee2929e12e19a3b1").unwrap() };
         assert_eq!(msg.encode(), raw);
-        assert_eq!(ControlPacket::decode(&raw), Some(msg));
+        assert_eq!(ControlPacket::decode(&raw), Ok(msg));
     }
 
     #[test]
@@ -244,7 +418,7 @@ mod test {
         let raw = Vec::from_hex("497400049d74e35b0000001280534c66df69e44b496d5bc8").unwrap();
         let msg = ControlPacket::Pong { version: 18, opaque_data: Vec::from_hex("80534c66df69e44b496d5bc8").unwrap() };
         assert_eq!(msg.encode(), raw);
-        assert_eq!(ControlPacket::decode(&raw), Some(msg));
+        assert_eq!(ControlPacket::decode(&raw), Ok(msg));
     }
 
     #[test]
@@ -254,7 +428,7 @@ mod test {
         let key = decode_base32(b"3fdqgz2vtqb0wx02hhvx3wjmjqktyt567fcuvj3m72vw5u6ubu740k3m22fplqvqwpspy93").unwrap();
         let msg = ControlPacket::KeyPing { version: 18, opaque_data: vec![], key: key };
         assert_eq!(msg.encode(), raw);
-        assert_eq!(ControlPacket::decode(&raw), Some(msg));
+        assert_eq!(ControlPacket::decode(&raw), Ok(msg));
     }
 
     #[test]
@@ -264,7 +438,7 @@ mod test {
         let key = decode_base32(b"cmnkylz1dx8mx3bdxku80yw20gqmg0s9nsrusdv0psnxnfhqfmu40k3m22fplqvqwpspy93").unwrap();
         let msg = ControlPacket::KeyPong { version: 18, opaque_data: vec![], key: key };
         assert_eq!(msg.encode(), raw);
-        assert_eq!(ControlPacket::decode(&raw), Some(msg));
+        assert_eq!(ControlPacket::decode(&raw), Ok(msg));
     }
 
     #[test]
@@ -272,8 +446,141 @@ mod test {
         let raw = Vec::from_hex("bce300020000000a62c1d23a648114010379000000012d7c000006c378e071c46aefad3aa295fff396371d10678e9833807de083a4a40da39bf0f68f15c4380afbe92405196242a74bb304a8285088579f94fb01867be2171aa8d2c7b54198a89bbdb80c668e9c05").unwrap();
         let msg = ControlPacket::decode(&raw).unwrap();
         match msg {
-            ControlPacket::Error { type_, .. } => assert_eq!(type_, ErrorType::ReturnPathInvalid),
+            ControlPacket::Error { ref type_, .. } => assert_eq!(type_, &ErrorType::ReturnPathInvalid),
             _ => assert!(false),
         }
+        assert_eq!(msg.encode(), raw);
+    }
+
+    #[test]
+    fn error_types() {
+        let types = vec![
+            ErrorType::None,
+            ErrorType::MalformedAddress,
+            ErrorType::Flood,
+            ErrorType::LinkLimitExceeded,
+            ErrorType::OversizeMessage,
+            ErrorType::UndersizeMessage,
+            ErrorType::Authentication,
+            ErrorType::Invalid,
+            ErrorType::Undeliverable,
+            ErrorType::LoopRoute,
+            ErrorType::ReturnPathInvalid,
+        ];
+        for (code, type_) in types.into_iter().enumerate() {
+            let msg = ControlPacket::Error { type_: type_, cause: vec![1, 2, 3, 4] };
+            let raw = msg.encode();
+            assert_eq!(BigEndian::read_u16(&raw[2..4]), 2);
+            assert_eq!(BigEndian::read_u32(&raw[4..8]), code as u32);
+            assert_eq!(&raw[8..], &[1, 2, 3, 4]);
+            assert_eq!(ControlPacket::decode(&raw), Ok(msg));
+        }
+
+        let mut raw = ControlPacket::Error { type_: ErrorType::None, cause: vec![] }.encode();
+        BigEndian::write_u32(&mut raw[4..8], 11);
+        let raw = with_checksum(&raw);
+        assert_eq!(ControlPacket::decode(&raw), Err(ControlError::UnknownErrorType(11)));
+    }
+
+    #[test]
+    fn encode_decode() {
+        let packets = vec![
+            ControlPacket::Error { type_: ErrorType::Undeliverable, cause: vec![0; 12] },
+            ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] },
+            ControlPacket::Pong { version: 18, opaque_data: vec![8, 7, 6, 5, 4, 3, 2, 1] },
+            ControlPacket::KeyPing { version: 17, opaque_data: vec![1, 2, 3], key: vec![4; 44] },
+            ControlPacket::KeyPong { version: 17, opaque_data: vec![5], key: vec![6; 44] },
+        ];
+        for packet in packets {
+            assert_eq!(ControlPacket::decode(&packet.encode()), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn peer_version() {
+        for version in vec![17, 18, 20] {
+            let builder = ControlPacketBuilder::new().version(version);
+            let ping = ControlPacket::decode(&builder.ping().encode()).unwrap();
+            assert_eq!(ping.peer_version(), Some(version));
+            let pong = ControlPacket::decode(&builder.pong_to(&ping).unwrap().encode()).unwrap();
+            assert_eq!(pong.peer_version(), Some(version));
+        }
+        // The pong reports the version of the node replying.
+        let ping = ControlPacketBuilder::new().version(17).ping();
+        let pong = ControlPacketBuilder::new().version(18).pong_to(&ping).unwrap();
+        assert_eq!(pong.peer_version(), Some(18));
+
+        let error = ControlPacket::Error { type_: ErrorType::Flood, cause: vec![] };
+        assert_eq!(error.peer_version(), None);
+    }
+
+    #[test]
+    fn opaque_data_length() {
+        for length in vec![0, 1, 6, 8, 512] {
+            let opaque_data = (0..length).map(|i| i as u8).collect::<Vec<u8>>();
+            let ping = ControlPacket::Ping { version: 18, opaque_data: opaque_data.clone() };
+            let raw = ping.encode();
+            assert_eq!(raw.len(), 12 + length);
+            assert_eq!(ControlPacket::decode(&raw), Ok(ping));
+            let pong = ControlPacket::Pong { version: 18, opaque_data: opaque_data.clone() };
+            assert_eq!(ControlPacket::decode(&pong.encode()), Ok(pong));
+            let key_ping = ControlPacket::KeyPing { version: 18, opaque_data: opaque_data, key: vec![1; 44] };
+            assert_eq!(ControlPacket::decode(&key_ping.encode()), Ok(key_ping));
+        }
+
+        // Fixed fields cannot be truncated.
+        let raw = ControlPacket::KeyPing { version: 18, opaque_data: vec![], key: vec![1; 44] }.encode();
+        assert_eq!(ControlPacket::decode(&with_checksum(&raw[..55])), Err(ControlError::Truncated(55)));
+        let raw = ControlPacket::Ping { version: 18, opaque_data: vec![] }.encode();
+        assert_eq!(ControlPacket::decode(&with_checksum(&raw[..11])), Err(ControlError::Truncated(11)));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let raw = Vec::from_hex("a2e5000309f91102000000124d160b1eee2929e12e19a3b1").unwrap();
+        assert!(ControlPacket::decode(&raw).is_ok());
+        for i in 0..raw.len() {
+            let mut corrupted = raw.clone();
+            corrupted[i] ^= 0x01;
+            assert_eq!(ControlPacket::decode(&corrupted), Err(ControlError::BadChecksum));
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let builder = ControlPacketBuilder::new();
+        let ping = builder.ping();
+        let opaque_data = match ping {
+            ControlPacket::Ping { version: 18, ref opaque_data } => opaque_data.clone(),
+            ref p => panic!("Not a ping: {:?}", p),
+        };
+        assert_eq!(ControlPacket::decode(&ping.encode()), Ok(ping.clone()));
+        assert_eq!(builder.pong_to(&ping), Some(ControlPacket::Pong { version: 18, opaque_data: opaque_data }));
+        assert_eq!(builder.pong_to(&builder.key_ping(vec![0; 44])), None);
+
+        let key_ping = ControlPacketBuilder::new().version(17).key_ping(vec![1; 44]);
+        match key_ping {
+            ControlPacket::KeyPing { version: 17, ref key, .. } => assert_eq!(key, &vec![1; 44]),
+            ref p => panic!("Not a key ping: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn ping_latency() {
+        use std::time::Duration;
+        let mut clock = 1_000_000u64;
+        let ping = ControlPacket::ping_with_timestamp(clock);
+        let raw = ping.encode();
+        clock += 42;
+
+        let ping = ControlPacket::decode(&raw).unwrap();
+        let pong = ControlPacketBuilder::new().pong_to(&ping).unwrap();
+        let pong = ControlPacket::decode(&pong.encode()).unwrap();
+        assert_eq!(pong.pong_latency(clock), Some(Duration::from_millis(42)));
+
+        assert_eq!(pong.pong_latency(1_000_000 - 1), None); // Clock went backward
+        assert_eq!(ping.pong_latency(clock), None); // Not a pong
+        let pong = ControlPacket::Pong { version: 18, opaque_data: vec![1, 2, 3, 4] };
+        assert_eq!(pong.pong_latency(clock), None); // No timestamp
     }
 }
@@ -1,6 +1,7 @@
 /// https://github.com/cjdelisle/cjdns/blob/cjdns-v18/wire/DataHeader.h
 
 use std::fmt;
+use std::error;
 
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
@@ -12,6 +13,45 @@ pub enum Payload {
     RoutePacket(route_packet::RoutePacket),
 }
 
+impl Payload {
+    /// Wraps this payload in a new `DataPacket`; this is the reverse
+    /// operation of `DataPacket::payload`.
+    pub fn to_data_packet(&self, version: u8) -> DataPacket {
+        DataPacket::new(version, self)
+    }
+}
+
+/// An error returned by `DataPacket::payload`
+#[derive(Debug)]
+pub enum DataPacketError {
+    /// The packet is shorter than a data header; the argument is its
+    /// length.
+    TooShort(usize),
+    /// The content type is not supported.
+    UnknownContentType(u16),
+    /// The content is a route packet which cannot be decoded.
+    RoutePacket(route_packet::RoutePacketError),
+}
+
+impl fmt::Display for DataPacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DataPacketError::TooShort(length) => write!(f, "too short ({} bytes)", length),
+            DataPacketError::UnknownContentType(content_type) => write!(f, "unknown content type {}", content_type),
+            DataPacketError::RoutePacket(ref e) => write!(f, "bad route packet: {}", e),
+        }
+    }
+}
+
+impl error::Error for DataPacketError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DataPacketError::TooShort(_) | DataPacketError::UnknownContentType(_) => None,
+            DataPacketError::RoutePacket(ref e) => Some(e),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DataPacket {
     pub raw: Vec<u8>,
@@ -46,16 +86,19 @@ impl DataPacket {
         BigEndian::read_u16(&self.raw[2..4])
     }
 
-    pub fn payload(self) -> Result<Payload, String> {
+    pub fn payload(self) -> Result<Payload, DataPacketError> {
+        if self.raw.len() < 4 {
+            return Err(DataPacketError::TooShort(self.raw.len()))
+        }
         let content_type = self.content_type();
         match content_type {
             256 => {
                 match route_packet::RoutePacket::decode(&self.raw[4..]) {
                     Ok(packet) => Ok(Payload::RoutePacket(packet)),
-                    Err(e) => Err(format!("Could not decode route packet: {:?}", e)), // TODO: proper error handling
+                    Err(e) => Err(DataPacketError::RoutePacket(e)),
                 }
             },
-            _ => panic!(format!("Unknown Data Packet Content-Type: {}", content_type)),
+            _ => Err(DataPacketError::UnknownContentType(content_type)),
         }
     }
 }
@@ -65,3 +108,30 @@ impl fmt::Display for DataPacket {
         write!(f, "DataPacket(version={}, payload={:?})", self.version(), self.clone().payload())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use route_packet::RoutePacketBuilder;
+
+    #[test]
+    fn payload_to_data_packet() {
+        let route_packet = RoutePacketBuilder::new(18, b"12345".to_vec())
+                .query("gp".to_owned())
+                .target_address(vec![0, 0, 0, 0, 0, 0, 0, 0])
+                .finalize();
+        let data_packet = DataPacket::new(1, &Payload::RoutePacket(route_packet));
+
+        let mut route_packet = match data_packet.payload().unwrap() {
+            Payload::RoutePacket(route_packet) => route_packet,
+        };
+        route_packet.transaction_id = b"67890".to_vec();
+
+        let data_packet = Payload::RoutePacket(route_packet.clone()).to_data_packet(1);
+        assert_eq!(data_packet.version(), 1);
+        assert_eq!(data_packet.content_type(), 256);
+        match data_packet.payload().unwrap() {
+            Payload::RoutePacket(decoded) => assert_eq!(decoded, route_packet),
+        }
+    }
+}
@@ -7,6 +7,8 @@
 //! network) or by turning it `into_iter`ator of `EncodingSchemeForm`.
 
 use std::iter::FromIterator;
+use std::fmt;
+use std::error;
 
 /// An item of the Encoding Scheme.
 /// See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#definitions
@@ -18,6 +20,28 @@ pub struct EncodingSchemeForm {
     pub prefix_length: u8,
 }
 
+/// An error returned by `EncodingScheme::deserialize`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SchemeError {
+    /// The bytes are not the canonical serialization of the forms they
+    /// contain (eg. they are padded, or followed by garbage).
+    NotCanonical,
+    /// The scheme cannot be used to encode labels (see
+    /// `EncodingScheme::is_sane`).
+    NotSane,
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemeError::NotCanonical => write!(f, "not canonical"),
+            SchemeError::NotSane => write!(f, "not sane"),
+        }
+    }
+}
+
+impl error::Error for SchemeError {}
+
 /// A list of `EncodingSchemeForm`. Can be serialized to/deserialized from
 /// bytes, and constructed from/read to an iterator of `EncodingSchemeForm`.
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -37,6 +61,75 @@ impl EncodingScheme {
     pub fn into_bytes(self) -> Vec<u8> {
         self.bytes
     }
+
+    /// Parses a scheme received from the network (eg. in the `es` field
+    /// of route packets).
+    ///
+    /// Fails if `bytes` is not the canonical serialization of a scheme,
+    /// or if the scheme is not sane (see `is_sane`).
+    pub fn deserialize(bytes: &[u8]) -> Result<EncodingScheme, SchemeError> {
+        let scheme = EncodingScheme::new(bytes.to_vec());
+        let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+        if EncodingScheme::from_iter(forms.iter()) != scheme {
+            return Err(SchemeError::NotCanonical)
+        }
+        if !scheme.is_sane() {
+            return Err(SchemeError::NotSane)
+        }
+        Ok(scheme)
+    }
+
+    /// Checks this scheme can be used to encode and decode labels,
+    /// with the same rules as cjdns' `EncodingScheme_isSane`:
+    ///
+    /// * a scheme has between 1 and 31 forms,
+    /// * a fixed-width scheme (only one form) has no prefix,
+    /// * forms of a variable-width scheme all have a prefix, and
+    ///   they are sorted by strictly increasing `bit_count`,
+    /// * no prefix is a prefix of another one,
+    /// * every director fits in a label.
+    pub fn is_sane(&self) -> bool {
+        let forms = self.clone().into_iter().collect::<Vec<_>>();
+        if forms.is_empty() || forms.len() > 31 {
+            return false
+        }
+        if forms.len() == 1 {
+            let form = &forms[0];
+            return form.prefix_length == 0 && form.prefix == 0 && form.bit_count > 0
+        }
+        for (i, form) in forms.iter().enumerate() {
+            if form.prefix_length == 0 || form.bit_count == 0 {
+                return false
+            }
+            if form.prefix_length + form.bit_count > 59 {
+                return false
+            }
+            for previous in &forms[..i] {
+                if form.bit_count <= previous.bit_count {
+                    return false
+                }
+                // Compare the prefixes on the length of the shortest one
+                let length = ::std::cmp::min(form.prefix_length, previous.prefix_length);
+                let mask = (1u64 << length) - 1;
+                if form.prefix & mask == previous.prefix & mask {
+                    return false
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the number of forms of this scheme; valid `ei` fields of
+    /// route packets are lower than it.
+    pub fn form_count(&self) -> usize {
+        self.clone().into_iter().count()
+    }
+
+    /// Returns the index of the given form in this scheme, as sent in
+    /// the `ei` field of route packets.
+    pub fn index_of(&self, form: &EncodingSchemeForm) -> Option<u64> {
+        self.clone().into_iter().position(|f| f == *form).map(|i| i as u64)
+    }
 }
 
 impl IntoIterator for EncodingScheme {
@@ -48,23 +141,33 @@ impl IntoIterator for EncodingScheme {
     }
 }
 
+/// Iterates over the forms of a scheme without cloning it.
+impl<'a> IntoIterator for &'a EncodingScheme {
+    type Item = EncodingSchemeForm;
+    type IntoIter = EncodingSchemeIterator<&'a [u8]>;
+
+    fn into_iter(self) -> EncodingSchemeIterator<&'a [u8]> {
+        EncodingSchemeIterator { bytes: &self.bytes, window: 0, bits_in_window: 0, bytes_offset: 0 }
+    }
+}
+
 /// Iterator of `EncodingSchemeForm`, constructed from an instance of
-/// `EncodingScheme`
+/// `EncodingScheme` (or a reference to it)
 #[derive(Debug)]
-pub struct EncodingSchemeIterator {
-    bytes: Vec<u8>,
+pub struct EncodingSchemeIterator<B = Vec<u8>> {
+    bytes: B,
     window: u64, // integer view of a slice of the buffer (in little endian)
     bits_in_window: u8,
     bytes_offset: usize,
 }
 
-impl Iterator for EncodingSchemeIterator {
+impl<B: AsRef<[u8]>> Iterator for EncodingSchemeIterator<B> {
     type Item = EncodingSchemeForm;
 
     fn next(&mut self) -> Option<EncodingSchemeForm> {
         // Load new bytes in the window, so it is larger than any possible form
         while self.bits_in_window < 5+5+0b11111 {
-            let byte = *self.bytes.get(self.bytes_offset).unwrap_or(&0);
+            let byte = *self.bytes.as_ref().get(self.bytes_offset).unwrap_or(&0);
             self.window = self.window + ((byte as u64) << self.bits_in_window);
             self.bytes_offset += 1;
             self.bits_in_window += 8;
@@ -74,7 +177,7 @@ impl Iterator for EncodingSchemeIterator {
             None
         }
         else {
-            assert!(self.bytes_offset <= self.bytes.len()+(5+5+0b11111)/8);
+            assert!(self.bytes_offset <= self.bytes.as_ref().len()+(5+5+0b11111)/8);
             let prefix_length = self.window as u8 & 0b11111;
             self.window >>= 5;
             self.bits_in_window -= 5;
@@ -168,5 +271,76 @@ mod tests {
         assert_eq!(EncodingScheme::from_iter(forms.iter()).into_bytes(), encoding);
         assert_eq!(EncodingScheme::new(encoding.clone()).into_iter().collect::<Vec<_>>(), forms);
         assert_eq!(EncodingScheme::from_iter(forms.iter()).into_iter().collect::<Vec<_>>(), forms);
+        assert_eq!((&EncodingScheme::new(encoding)).into_iter().collect::<Vec<_>>(), forms);
+    }
+
+    #[test]
+    fn test_deserialize() {
+        // Canonical schemes
+        let v358 = vec![
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+            EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+        ];
+        let v358 = EncodingScheme::from_iter(v358.iter());
+        assert_eq!(EncodingScheme::deserialize(v358.bytes()), Ok(v358.clone()));
+        assert!(EncodingScheme::deserialize(&[0b011_00000, 0b00]).is_ok());
+        assert_eq!(EncodingScheme::deserialize(&[0b011_00000]), Err(SchemeError::NotCanonical)); // truncated
+        assert_eq!(EncodingScheme::deserialize(&[0b011_00000, 0b00, 0b00]), Err(SchemeError::NotCanonical)); // padded
+        assert!(EncodingScheme::deserialize(b"\x4f\xf4\xff\x29\xd9\xff\x7f\x89\xee\xff\x07").is_ok());
+
+        // Trailing garbage
+        let mut bytes = v358.clone().into_bytes();
+        bytes.extend_from_slice(&[0, 0, 1]);
+        assert_eq!(EncodingScheme::deserialize(&bytes), Err(SchemeError::NotCanonical));
+
+        // No form
+        assert_eq!(EncodingScheme::deserialize(&[]), Err(SchemeError::NotSane));
+
+        // Fixed-width with a prefix
+        let forms = vec![EncodingSchemeForm { prefix: 1, bit_count: 3, prefix_length: 1 }];
+        assert_eq!(EncodingScheme::deserialize(EncodingScheme::from_iter(forms.iter()).bytes()), Err(SchemeError::NotSane));
+
+        // Not sorted by bit_count
+        let forms = vec![
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+        ];
+        assert_eq!(EncodingScheme::deserialize(EncodingScheme::from_iter(forms.iter()).bytes()), Err(SchemeError::NotSane));
+
+        // Ambiguous prefixes
+        let forms = vec![
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b11, bit_count: 5, prefix_length: 2 },
+        ];
+        assert_eq!(EncodingScheme::deserialize(EncodingScheme::from_iter(forms.iter()).bytes()), Err(SchemeError::NotSane));
+    }
+
+    #[test]
+    fn test_form_count() {
+        assert_eq!(EncodingScheme::new(vec![0b011_00000, 0b00]).form_count(), 1);
+        let v358 = vec![
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+            EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+        ];
+        assert_eq!(EncodingScheme::from_iter(v358.iter()).form_count(), 3);
+        assert_eq!(EncodingScheme::from_iter(v358[..2].iter()).form_count(), 2);
+        assert_eq!(EncodingScheme::new(b"\x4f\xf4\xff\x29\xd9\xff\x7f\x89\xee\xff\x07".to_vec()).form_count(), 3);
+        assert_eq!(EncodingScheme::new(vec![]).form_count(), 0);
+    }
+
+    #[test]
+    fn test_index_of() {
+        let forms = vec![
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+            EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+        ];
+        let scheme = EncodingScheme::from_iter(forms.iter());
+        assert_eq!(scheme.index_of(&forms[0]), Some(0));
+        assert_eq!(scheme.index_of(&forms[1]), Some(1));
+        assert_eq!(scheme.index_of(&forms[2]), Some(2));
+        assert_eq!(scheme.index_of(&EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }), None);
     }
 }
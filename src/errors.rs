@@ -0,0 +1,147 @@
+//! Aggregates the errors of the other modules of this crate, for
+//! callers which do not need to tell them apart.
+
+use std::error;
+use std::fmt;
+
+use switch_packet::{SwitchParseError, PayloadError, SwitchError};
+use data_packet::DataPacketError;
+use route_packet::RoutePacketError;
+use control::ControlError;
+use encoding_scheme::SchemeError;
+use switch_core::KeyError;
+
+#[derive(Debug)]
+pub enum Error {
+    SwitchParse(SwitchParseError),
+    SwitchPayload(PayloadError),
+    Switch(SwitchError),
+    DataPacket(DataPacketError),
+    RoutePacket(RoutePacketError),
+    Control(ControlError),
+    Scheme(SchemeError),
+    Key(KeyError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::SwitchParse(ref e) => write!(f, "invalid switch packet: {}", e),
+            Error::SwitchPayload(ref e) => write!(f, "invalid switch packet payload: {}", e),
+            Error::Switch(ref e) => write!(f, "cannot switch packet: {}", e),
+            Error::DataPacket(ref e) => write!(f, "invalid data packet: {}", e),
+            Error::RoutePacket(ref e) => write!(f, "invalid route packet: {}", e),
+            Error::Control(ref e) => write!(f, "invalid control packet: {}", e),
+            Error::Scheme(ref e) => write!(f, "invalid encoding scheme: {}", e),
+            Error::Key(ref e) => write!(f, "invalid keys: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::SwitchParse(ref e) => Some(e),
+            Error::SwitchPayload(ref e) => Some(e),
+            Error::Switch(ref e) => Some(e),
+            Error::DataPacket(ref e) => Some(e),
+            Error::RoutePacket(ref e) => Some(e),
+            Error::Control(ref e) => Some(e),
+            Error::Scheme(ref e) => Some(e),
+            Error::Key(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<SwitchParseError> for Error {
+    fn from(e: SwitchParseError) -> Error {
+        Error::SwitchParse(e)
+    }
+}
+
+impl From<PayloadError> for Error {
+    fn from(e: PayloadError) -> Error {
+        Error::SwitchPayload(e)
+    }
+}
+
+impl From<SwitchError> for Error {
+    fn from(e: SwitchError) -> Error {
+        Error::Switch(e)
+    }
+}
+
+impl From<DataPacketError> for Error {
+    fn from(e: DataPacketError) -> Error {
+        Error::DataPacket(e)
+    }
+}
+
+impl From<RoutePacketError> for Error {
+    fn from(e: RoutePacketError) -> Error {
+        Error::RoutePacket(e)
+    }
+}
+
+impl From<ControlError> for Error {
+    fn from(e: ControlError) -> Error {
+        Error::Control(e)
+    }
+}
+
+impl From<SchemeError> for Error {
+    fn from(e: SchemeError) -> Error {
+        Error::Scheme(e)
+    }
+}
+
+impl From<KeyError> for Error {
+    fn from(e: KeyError) -> Error {
+        Error::Key(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_sub_errors() {
+        match Error::from(SwitchParseError::HeaderTooShort(4)) {
+            Error::SwitchParse(SwitchParseError::HeaderTooShort(4)) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(PayloadError::Truncated(14)) {
+            Error::SwitchPayload(PayloadError::Truncated(14)) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(SwitchError::LabelExhausted) {
+            Error::Switch(SwitchError::LabelExhausted) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(DataPacketError::UnknownContentType(42)) {
+            Error::DataPacket(DataPacketError::UnknownContentType(42)) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(RoutePacketError::EncodingIndexOutOfRange(3)) {
+            Error::RoutePacket(RoutePacketError::EncodingIndexOutOfRange(3)) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(ControlError::UnknownType(42)) {
+            Error::Control(ControlError::UnknownType(42)) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(SchemeError::NotSane) {
+            Error::Scheme(SchemeError::NotSane) => (),
+            e => panic!("{:?}", e),
+        }
+        match Error::from(KeyError::KeyMismatch) {
+            Error::Key(KeyError::KeyMismatch) => (),
+            e => panic!("{:?}", e),
+        }
+
+        let e: Error = ControlError::Truncated(2).into();
+        assert_eq!(format!("{}", e), "invalid control packet: truncated (2 bytes)");
+        assert_eq!(format!("{}", error::Error::source(&e).unwrap()), "truncated (2 bytes)");
+    }
+}
@@ -1,9 +1,8 @@
 extern crate hex;
 extern crate byteorder;
 extern crate simple_bencode;
-
-#[cfg(test)]
 extern crate fcp_cryptoauth;
+extern crate rand;
 
 pub mod operation;
 pub mod control;
@@ -11,6 +10,13 @@ pub mod switch_packet;
 pub mod data_packet;
 pub mod route_packet;
 pub mod encoding_scheme;
+pub mod session;
+pub mod switch_core;
+pub mod errors;
+
+/// Version of the cjdns protocol implemented by this crate, sent
+/// in the packets it builds.
+pub const PROTOCOL_VERSION: u32 = 18;
 
 #[cfg(test)]
 mod tests {
@@ -5,11 +5,28 @@ extern crate byteorder;
 use byteorder::ByteOrder;
 use byteorder::BigEndian;
 
+use encoding_scheme::{EncodingScheme, EncodingSchemeForm};
+
 /// An encoding of a path in the network
 pub type Label = [u8; 8];
 /// An interface identifier, unique to a node.
 pub type Director = u64;
 
+/// The label of the self interface of the switch handling the packet:
+/// a single self-interface director.
+/// (`Label` is an alias of an array, so this cannot be `Label::SELF`.)
+pub const SELF_LABEL: Label = [0, 0, 0, 0, 0, 0, 0, 0b0001];
+/// The label of an unknown destination. It contains no director at all,
+/// so it can never reach a self-interface director; instead, the first
+/// switch handling it delivers it to its self interface (eg. so its
+/// router can answer a discovery query).
+pub const WILDCARD_LABEL: Label = [0; 8];
+/// The sentinel cjdns uses (as `UINT64_MAX`) for a label which could not
+/// be computed, eg. because the path would not fit in 64 bits.
+/// It is not a route: its first director is not a self-interface
+/// director, and none of its directors is.
+pub const EXHAUSTED_LABEL: Label = [0xff; 8];
+
 /// Representation of where the packet should be sent, according to the label.
 #[derive(Eq)]
 #[derive(PartialEq)]
@@ -21,8 +38,38 @@ pub enum RoutingDecision {
     /// https://github.com/cjdelisle/cjdns/blob/cjdns-v17.4/doc/Whitepaper.md#self-interface-director
     SelfInterface(Director),
     /// The packet should be forwarded to the interface identified
-    /// by the argument
+    /// by the argument, which is the director consumed from the label
+    /// (not reversed).
     Forward(Director),
+    /// The packet would be forwarded to the interface it came from
+    /// (identified by the argument), which is what cjdns calls
+    /// a loop route. It should not be forwarded.
+    LoopRoute(Director),
+}
+
+impl RoutingDecision {
+    /// Returns the director which was shifted out of the label to take
+    /// this decision, eg. to log the path of a packet.
+    ///
+    /// For a `WILDCARD_LABEL`, no director is consumed, and this returns
+    /// the canonical self-interface director `0b0001`.
+    pub fn consumed_director(&self) -> Director {
+        match *self {
+            RoutingDecision::SelfInterface(director) |
+            RoutingDecision::Forward(director) |
+            RoutingDecision::LoopRoute(director) => director,
+        }
+    }
+
+    /// Returns the id of the interface the packet should be forwarded
+    /// to (the consumed director, as switches use directors as interface
+    /// ids), or None if it should not be forwarded to a peer.
+    pub fn iface_id(&self) -> Option<u8> {
+        match *self {
+            RoutingDecision::Forward(director) if director <= u8::max_value() as Director => Some(director as u8),
+            _ => None,
+        }
+    }
 }
 
 /// Shift bits to the right, collects the discarded bits, and puts these
@@ -57,6 +104,33 @@ fn test_right_shift_collect() {
     assert_eq!(0b1, bits);
 }
 
+/// Returns whether the director is a self-interface director, as defined by
+/// https://github.com/cjdelisle/cjdns/blob/cjdns-v17.4/doc/Whitepaper.md#self-interface-director
+fn is_self_director(director: Director) -> bool {
+    director & 0b1111 == 0b0001
+}
+
+/// Reads the first director of a label (ie. its least significant bits),
+/// using the forms of an encoding scheme to find its length.
+/// Returns (the director, including its prefix, director length), or None
+/// if no form matches.
+fn first_director(label: u64, forms: &[EncodingSchemeForm]) -> Option<(Director, u8)> {
+    first_form(label, forms).map(|form| {
+        let director_length = form.prefix_length + form.bit_count;
+        let (_, director) = right_shift_collect(label, director_length);
+        (director, director_length)
+    })
+}
+
+/// Returns the form of the first director of a label, or None if no form
+/// matches.
+pub fn first_form(label: u64, forms: &[EncodingSchemeForm]) -> Option<&EncodingSchemeForm> {
+    forms.iter().find(|form| {
+        let prefix_mask = (0b1u64 << form.prefix_length) - 1;
+        label & prefix_mask == form.prefix
+    })
+}
+
 pub fn label_from_u64(u: u64) -> Label {
     let mut label = [0u8; 8];
     BigEndian::write_u64(&mut label, u);
@@ -67,6 +141,43 @@ pub fn u64_from_label(label: Label) -> u64 {
     BigEndian::read_u64(&label)
 }
 
+/// Returns the number of meaningful bits of a label: the index of its
+/// highest set bit, plus one. Everything above is padding; once a
+/// switch consumed them all, the label is exhausted.
+///
+/// Only counts the bits of the path if there is no return path in the
+/// high bits of the label, ie. before it is switched
+/// (`SwitchPacket::label_shift` tells how many bits of a switched label
+/// are its return path).
+///
+/// # Examples
+///
+/// ```
+/// # use fcp_switching::operation::*;
+/// assert_eq!(remaining_bits(&label_from_u64(0b0001_101_011)), 7);
+/// assert_eq!(remaining_bits(&WILDCARD_LABEL), 0);
+/// ```
+pub fn remaining_bits(label: &Label) -> u8 {
+    (64 - u64_from_label(*label).leading_zeros()) as u8
+}
+
+#[test]
+fn test_remaining_bits() {
+    // Full label
+    assert_eq!(remaining_bits(&label_from_u64(0b1 << 63 | 0b101)), 64);
+    assert_eq!(remaining_bits(&[0xff; 8]), 64);
+
+    // Half-consumed label: two 3-bit directors out of four were popped.
+    let label = label_from_u64(0b0001_110_100_101_011);
+    assert_eq!(remaining_bits(&label), 13);
+    let (label, _) = right_shift_collect(u64_from_label(label), 6);
+    assert_eq!(remaining_bits(&label_from_u64(label)), 7);
+
+    // Only the self-interface director is left, then nothing.
+    assert_eq!(remaining_bits(&SELF_LABEL), 1);
+    assert_eq!(remaining_bits(&WILDCARD_LABEL), 0);
+}
+
 /// Performs a switch operation on the label (using constant director length),
 /// as defined by
 /// https://github.com/cjdelisle/cjdns/blob/cjdns-v17.4/doc/Whitepaper.md#operation :
@@ -103,6 +214,9 @@ pub fn u64_from_label(label: Label) -> u64 {
 /// assert_eq!(0b0110_010101_110110011_11001_1000000_000000000000000000000000000000000, u64_from_label(label));
 /// ```
 ///
+/// `WILDCARD_LABEL` is delivered to the self interface, as if it were
+/// `SELF_LABEL`.
+///
 /// Supports non-canonical self-interfaces:
 /// 
 /// ```
@@ -113,6 +227,7 @@ pub fn u64_from_label(label: Label) -> u64 {
 /// assert_eq!(0b100110_010101_110110011_11001_1000000_0000000000000000000000000000000, u64_from_label(label));
 /// ```
 pub fn switch(label: &Label, director_length: u8, reversed_origin_iface: &Director) -> (Label, RoutingDecision) {
+    let is_wildcard = *label == WILDCARD_LABEL;
     let label = BigEndian::read_u64(label);
     let (mut new_label, director) = right_shift_collect(label, director_length);
     assert!(reversed_origin_iface < &(0b1u64 << director_length));
@@ -121,16 +236,682 @@ pub fn switch(label: &Label, director_length: u8, reversed_origin_iface: &Direct
     let mut new_label_arr = [0u8; 8];
     BigEndian::write_u64(&mut new_label_arr, new_label);
 
-    if director & 0b1111 == 0b0001 {
-        // If it is a self-interface director, as defined by
-        // https://github.com/cjdelisle/cjdns/blob/cjdns-v17.4/doc/Whitepaper.md#self-interface-director
+    if is_wildcard {
+        // There is no director to pop, but the reverse path is recorded
+        // so the packet can be replied to.
+        (new_label_arr, RoutingDecision::SelfInterface(0b0001))
+    }
+    else if is_self_director(director) {
         (new_label_arr, RoutingDecision::SelfInterface(director))
     }
+    else if director == reverse_director(*reversed_origin_iface, director_length) {
+        (new_label_arr, RoutingDecision::LoopRoute(director))
+    }
     else {
         (new_label_arr, RoutingDecision::Forward(director))
     }
 }
 
+#[test]
+fn test_consumed_director() {
+    let label = label_from_u64(0b0001_101011_011010);
+    let (label, decision) = switch(&label, 6, &0b000110);
+    assert_eq!(decision, RoutingDecision::Forward(0b011010));
+    assert_eq!(decision.consumed_director(), 0b011010);
+    let (_, decision) = switch(&label, 6, &0b000110);
+    assert_eq!(decision.consumed_director(), 0b101011);
+
+    let (_, decision) = switch(&label_from_u64(0b0001_101), 3, &0b101);
+    assert_eq!(decision, RoutingDecision::LoopRoute(0b101));
+    assert_eq!(decision.consumed_director(), 0b101);
+
+    let (_, decision) = switch(&label_from_u64(0b0110001), 6, &0b000110);
+    assert_eq!(decision.consumed_director(), 0b110001);
+    assert_eq!(decision.iface_id(), None);
+}
+
+#[test]
+fn test_iface_id() {
+    let (_, decision) = switch(&label_from_u64(0b0001_101), 3, &0b110);
+    assert_eq!(decision.iface_id(), Some(0b101));
+    let (_, decision) = switch(&label_from_u64(0b0001_101), 3, &0b101);
+    assert_eq!(decision, RoutingDecision::LoopRoute(0b101));
+    assert_eq!(decision.iface_id(), None);
+    let (_, decision) = switch(&SELF_LABEL, 3, &0b110);
+    assert_eq!(decision.iface_id(), None);
+    // Too wide for an interface id
+    assert_eq!(RoutingDecision::Forward(0b1_0000_0000).iface_id(), None);
+}
+
+#[test]
+fn test_switch_special_labels() {
+    let (label, decision) = switch(&SELF_LABEL, 3, &0b110);
+    assert_eq!(decision, RoutingDecision::SelfInterface(0b0001));
+    assert_eq!(u64_from_label(label), 0b110 << 61);
+
+    let (label, decision) = switch(&SELF_LABEL, 4, &0b1100);
+    assert_eq!(decision, RoutingDecision::SelfInterface(0b0001));
+    assert_eq!(u64_from_label(label), 0b1100 << 60);
+
+    let (mut label, decision) = switch(&WILDCARD_LABEL, 3, &0b110);
+    assert_eq!(decision, RoutingDecision::SelfInterface(0b0001));
+    assert_eq!(u64_from_label(label), 0b110 << 61);
+    reverse_label(&mut label);
+    assert_eq!(u64_from_label(label), 0b011);
+}
+
+#[test]
+fn test_switch_loop_route() {
+    // Received from interface 0b011, and sent back to it.
+    let label = label_from_u64(0b001_011);
+    let (label, decision) = switch(&label, 3, &0b110);
+    assert_eq!(decision, RoutingDecision::LoopRoute(0b011));
+    assert_eq!(u64_from_label(label), 0b110 << 61 | 0b001);
+
+    let label = label_from_u64(0b001_011);
+    let (_, decision) = switch(&label, 3, &0b100);
+    assert_eq!(decision, RoutingDecision::Forward(0b011));
+}
+
+/// Reverse the bits of a director of the given length. Used to compute
+/// the reverse path of a packet, as the reversed origin interface.
+///
+/// # Examples
+///
+/// ```
+/// # use fcp_switching::operation::*;
+/// assert_eq!(reverse_director(0b011, 3), 0b110);
+/// assert_eq!(reverse_director(0b0001, 4), 0b1000);
+/// ```
+pub fn reverse_director(director: Director, director_length: u8) -> Director {
+    assert!(director_length <= 64);
+    assert!(director_length == 64 || director < (0b1u64 << director_length));
+    let mut reversed = 0;
+    for i in 0..director_length {
+        if director & (0b1u64 << i) != 0 {
+            reversed |= 0b1u64 << (director_length - 1 - i);
+        }
+    }
+    reversed
+}
+
+#[test]
+fn test_reverse_director() {
+    // Reversal table of 3-bit directors
+    let table = [0b000, 0b100, 0b010, 0b110, 0b001, 0b101, 0b011, 0b111];
+    for (director, reversed) in table.iter().enumerate() {
+        assert_eq!(reverse_director(director as Director, 3), *reversed);
+    }
+
+    for director_length in 1..16 {
+        for director in 0..(0b1u64 << director_length) {
+            let reversed = reverse_director(director, director_length);
+            assert!(reversed < (0b1u64 << director_length));
+            assert_eq!(reverse_director(reversed, director_length), director);
+        }
+    }
+
+    assert_eq!(reverse_director(0b1, 64), 0b1u64 << 63);
+    assert_eq!(reverse_director(0, 0), 0);
+}
+
+/// Extends a route by one hop: `director`, of the given form, is used
+/// by the last switch of the route to forward the packet further.
+///
+/// The self-interface director terminating the route (its highest set
+/// bit) is moved above the new director, rather than being shifted out
+/// of the label.
+/// Returns false, and leaves the label unchanged, if the label has no
+/// terminator (eg. `WILDCARD_LABEL`), if `director` does not fit in the
+/// form or does not end with its prefix, or if the extended route does
+/// not fit in a label.
+///
+/// ```
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let form = EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 };
+/// let mut label = label_from_u64(0b001_011);
+/// assert!(append_hop(&mut label, 0b101, &form));
+/// assert_eq!(u64_from_label(label), 0b001_101_011);
+/// ```
+pub fn append_hop(label: &mut Label, director: Director, form: &EncodingSchemeForm) -> bool {
+    let director_length = (form.prefix_length + form.bit_count) as u32;
+    let route = u64_from_label(*label);
+    if route == 0 || director_length >= 64 || director >= (0b1u64 << director_length) || is_self_director(director) {
+        return false
+    }
+    if director & ((0b1u64 << form.prefix_length) - 1) != form.prefix {
+        return false
+    }
+    let hops_length = 63 - route.leading_zeros(); // Position of the terminator
+    if hops_length + director_length >= 64 {
+        return false
+    }
+    let hops = route & ((0b1u64 << hops_length) - 1);
+    let new_route = hops | (director << hops_length) | (0b1u64 << (hops_length + director_length));
+    label.copy_from_slice(&label_from_u64(new_route));
+    true
+}
+
+#[test]
+fn test_append_hop() {
+    use std::iter::FromIterator;
+    let form = EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 };
+    let mut label = label_from_u64(0b001_011);
+    assert!(append_hop(&mut label, 0b101, &form));
+    assert_eq!(u64_from_label(label), 0b001_101_011);
+    assert_eq!(hops_to_self(&label, &EncodingScheme::from_iter(vec![form.clone()].iter())), 2);
+
+    let mut label = SELF_LABEL;
+    assert!(append_hop(&mut label, 0b011, &form));
+    assert_eq!(u64_from_label(label), 0b001_011);
+
+    let mut label = WILDCARD_LABEL;
+    assert!(!append_hop(&mut label, 0b011, &form));
+    let mut label = label_from_u64(0b001_011);
+    assert!(!append_hop(&mut label, 0b1011, &form)); // Too long for the form
+    let mut label = label_from_u64(0b1 << 62);
+    assert!(!append_hop(&mut label, 0b011, &form)); // Overflow
+    assert_eq!(u64_from_label(label), 0b1 << 62);
+
+    // Directors of a prefixed form must include the prefix.
+    let form = EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 };
+    let mut label = label_from_u64(0b001_011);
+    assert!(append_hop(&mut label, 0b10100_10, &form));
+    assert_eq!(u64_from_label(label), 0b001_10100_10_011);
+    let mut label = label_from_u64(0b001_011);
+    assert!(!append_hop(&mut label, 0b10100, &form)); // Without its prefix
+    assert_eq!(u64_from_label(label), 0b001_011);
+}
+
+/// Returns how many times a packet with this label will be forwarded
+/// before it reaches the self interface of a switch.
+///
+/// Only the directors before the self-interface director are counted,
+/// the reverse path accumulated in the most significant bits of the label
+/// is not.
+/// If the label does not contain a self-interface director, returns the
+/// number of directors before the label is exhausted.
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+/// let label = label_from_u64(0b001_101_011);
+/// assert_eq!(hops_to_self(&label, &scheme), 2);
+/// ```
+pub fn hops_to_self(label: &Label, scheme: &EncodingScheme) -> usize {
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    let mut label = u64_from_label(*label);
+    let mut hops = 0;
+    let mut remaining_bits = 64;
+    while label != 0 {
+        match first_director(label, &forms) {
+            Some((director, director_length)) => {
+                if is_self_director(director) || director_length > remaining_bits {
+                    break
+                }
+                let (new_label, _) = right_shift_collect(label, director_length);
+                label = new_label;
+                remaining_bits -= director_length;
+                hops += 1;
+            },
+            None => break,
+        }
+    }
+    hops
+}
+
+#[test]
+fn test_hops_to_self() {
+    use std::iter::FromIterator;
+    let scheme = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+
+    let label = label_from_u64(0b001_101_011);
+    assert_eq!(hops_to_self(&label, &scheme), 2);
+
+    // Partially traversed: the reverse path is in the most significant bits
+    let (label, _) = switch(&label, 3, &0b110);
+    assert_eq!(u64_from_label(label), 0b110 << 61 | 0b001_101);
+    assert_eq!(hops_to_self(&label, &scheme), 1);
+    let (label, _) = switch(&label, 3, &0b010);
+    assert_eq!(hops_to_self(&label, &scheme), 0);
+
+    // Variable-length scheme, from
+    // https://github.com/cjdelisle/cjdns/blob/cjdns-v18/switch/EncodingScheme.h
+    let scheme = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+    let label = label_from_u64(0b0001_01010101_00_10101_10_0111);
+    assert_eq!(hops_to_self(&label, &scheme), 3);
+    let label = label_from_u64(0b0001);
+    assert_eq!(hops_to_self(&label, &scheme), 0);
+}
+
+/// Decodes the interfaces a label goes through, up to the self-interface
+/// director, without their prefixes.
+/// The boolean is false if the label is exhausted (or cannot be decoded)
+/// before a self-interface director is reached.
+fn interfaces_to_self(label: &Label, forms: &[EncodingSchemeForm]) -> (Vec<u64>, bool) {
+    let mut label = u64_from_label(*label);
+    let mut interfaces = Vec::new();
+    let mut remaining_bits = 64;
+    while label != 0 {
+        let form = match first_form(label, forms) {
+            Some(form) => form,
+            None => break,
+        };
+        let director_length = form.prefix_length + form.bit_count;
+        if director_length > remaining_bits {
+            break
+        }
+        let (new_label, director) = right_shift_collect(label, director_length);
+        if is_self_director(director) {
+            return (interfaces, true)
+        }
+        interfaces.push(director >> form.prefix_length);
+        label = new_label;
+        remaining_bits -= director_length;
+    }
+    (interfaces, false)
+}
+
+/// Returns whether a label decodes cleanly with an encoding scheme:
+/// every director matches a form of the scheme, and a self-interface
+/// director is reached before the label is exhausted.
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+/// assert!(validate_route(&label_from_u64(0b001_101_011), &scheme));
+/// assert!(!validate_route(&label_from_u64(0b101_011), &scheme));
+/// ```
+pub fn validate_route(label: &Label, scheme: &EncodingScheme) -> bool {
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    let (_, reaches_self) = interfaces_to_self(label, &forms);
+    reaches_self
+}
+
+#[test]
+fn test_validate_route() {
+    use std::iter::FromIterator;
+    let scheme = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+    ].iter());
+    assert!(validate_route(&SELF_LABEL, &scheme));
+    assert!(validate_route(&label_from_u64(0b0001_00011_10_0111), &scheme));
+    assert!(!validate_route(&WILDCARD_LABEL, &scheme));
+    // No form with the 0b00 prefix
+    assert!(!validate_route(&label_from_u64(0b0001_00), &scheme));
+    // Exhausted before reaching a self-interface director
+    assert!(!validate_route(&label_from_u64(0b0111 << 60 | 0b0111), &scheme));
+}
+
+/// Returns whether a label is a route to the self interface of the first
+/// switch handling it, ie. it decodes cleanly with the scheme and its
+/// first director is a self-interface director.
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+/// assert!(is_self_route(&SELF_LABEL, &scheme));
+/// assert!(!is_self_route(&label_from_u64(0b001_011), &scheme));
+/// ```
+pub fn is_self_route(label: &Label, scheme: &EncodingScheme) -> bool {
+    validate_route(label, scheme) && hops_to_self(label, scheme) == 0
+}
+
+/// Returns an approximation of the cost of a route, to be used as a sort
+/// key when several labels reach the same node: lower is better.
+///
+/// Each hop costs 64, plus the width of its director, so fewer hops are
+/// always preferred, then narrower directors. Labels which do not
+/// decode cleanly (see `validate_route`) cost `u32::MAX`.
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+/// assert_eq!(label_cost(&label_from_u64(0b001_101_011), &scheme), 2*64 + 6);
+/// ```
+pub fn label_cost(label: &Label, scheme: &EncodingScheme) -> u32 {
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    let mut label = u64_from_label(*label);
+    let mut cost = 0u32;
+    let mut remaining_bits = 64;
+    while label != 0 {
+        let form = match first_form(label, &forms) {
+            Some(form) => form,
+            None => break,
+        };
+        let director_length = form.prefix_length + form.bit_count;
+        if director_length > remaining_bits {
+            break
+        }
+        let (new_label, director) = right_shift_collect(label, director_length);
+        if is_self_director(director) {
+            return cost
+        }
+        cost += 64 + director_length as u32;
+        label = new_label;
+        remaining_bits -= director_length;
+    }
+    ::std::u32::MAX
+}
+
+#[test]
+fn test_label_cost() {
+    use std::iter::FromIterator;
+    let scheme = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+    ].iter());
+    let one_hop = label_from_u64(0b0001_0111);
+    let three_hops = label_from_u64(0b0001_0101_0011_0111);
+    assert!(label_cost(&one_hop, &scheme) < label_cost(&three_hops, &scheme));
+    assert_eq!(label_cost(&SELF_LABEL, &scheme), 0);
+    // Same interface, in a wider form
+    assert!(label_cost(&one_hop, &scheme) < label_cost(&label_from_u64(0b0001_00011_10), &scheme));
+    assert_eq!(label_cost(&WILDCARD_LABEL, &scheme), ::std::u32::MAX);
+}
+
+/// Returns whether two labels go through the same interfaces, even if
+/// they are encoded with different forms (eg. an interface encoded in a
+/// wider form than needed).
+///
+/// Bits after the self-interface director (such as a reverse path) are
+/// ignored, as they do not affect routing.
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![
+///     EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+///     EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+/// ].iter());
+/// // Interface 3, then self; with interface 3 in the short and long forms.
+/// assert!(routes_equal(label_from_u64(0b0001_0111), label_from_u64(0b0001_00011_10), &scheme));
+/// ```
+pub fn routes_equal(a: Label, b: Label, scheme: &EncodingScheme) -> bool {
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    interfaces_to_self(&a, &forms) == interfaces_to_self(&b, &forms)
+}
+
+#[test]
+fn test_routes_equal() {
+    use std::iter::FromIterator;
+    let scheme = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+
+    // Interfaces 3 and 5, then self; padded to the 5-bit and 8-bit forms.
+    let unpadded = label_from_u64(0b0001_1011_0111);
+    let padded = label_from_u64(0b0001_00000101_00_00011_10);
+    assert!(routes_equal(unpadded, padded, &scheme));
+    assert!(routes_equal(padded, unpadded, &scheme));
+
+    // The reverse path after the self-interface director is ignored
+    let with_reverse_path = label_from_u64(0b101 << 61 | 0b0001_1011_0111);
+    assert!(routes_equal(unpadded, with_reverse_path, &scheme));
+
+    // Different interfaces
+    let other = label_from_u64(0b0001_0111_1011);
+    assert!(!routes_equal(unpadded, other, &scheme));
+
+    // Same prefix, but one does not reach self
+    assert!(!routes_equal(label_from_u64(0b0001_0111), label_from_u64(0b0111), &scheme));
+}
+
+/// Returns the shortest label going through the same interfaces as
+/// `label` (see `routes_equal`): each director is encoded with the
+/// smallest form it fits in, and the bits after the self-interface
+/// director are dropped.
+///
+/// Labels which do not reach a self-interface director (see
+/// `validate_route`) are returned unchanged.
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![
+///     EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+///     EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+/// ].iter());
+/// let label = canonical_label(&label_from_u64(0b0001_00011_10), &scheme);
+/// assert_eq!(u64_from_label(label), 0b0001_0111);
+/// ```
+pub fn canonical_label(label: &Label, scheme: &EncodingScheme) -> Label {
+    let mut forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    let (interfaces, reaches_self) = interfaces_to_self(label, &forms);
+    if !reaches_self {
+        return *label
+    }
+    let self_form = first_form(0b0001, &forms).unwrap(); // Reaches self, so some form matches
+    let self_length = self_form.prefix_length + self_form.bit_count;
+
+    forms.sort_by_key(|form| form.prefix_length + form.bit_count);
+    let mut canonical = 0u64;
+    let mut length = 0u8;
+    for interface in interfaces {
+        for form in forms.iter() {
+            if form.bit_count < 64 && interface >= (1u64 << form.bit_count) {
+                continue // Does not fit in this form
+            }
+            let director = (interface << form.prefix_length) | form.prefix;
+            if is_self_director(director) {
+                continue // Would be read as a self-interface director
+            }
+            canonical |= director << length;
+            length += form.prefix_length + form.bit_count;
+            break
+        }
+    }
+    assert!(length + self_length <= 64);
+    canonical |= 0b0001 << length;
+    label_from_u64(canonical)
+}
+
+#[test]
+fn test_canonical_label() {
+    use std::iter::FromIterator;
+    let scheme = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+
+    // Interfaces 3 and 5, then self.
+    let unpadded = label_from_u64(0b0001_1011_0111);
+    let padded = label_from_u64(0b0001_00000101_00_00011_10);
+    let with_reverse_path = label_from_u64(0b101 << 61 | 0b0001_1011_0111);
+    assert_eq!(canonical_label(&unpadded, &scheme), unpadded);
+    assert_eq!(canonical_label(&padded, &scheme), unpadded);
+    assert_eq!(canonical_label(&with_reverse_path, &scheme), unpadded);
+
+    // Interface 20 does not fit in the first form
+    let label = label_from_u64(0b0001_00010100_00);
+    assert_eq!(canonical_label(&label, &scheme), label_from_u64(0b0001_10100_10));
+
+    // Fixed-width scheme: nothing to shorten.
+    let scheme = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+    let label = label_from_u64(0b001_101_011);
+    assert_eq!(canonical_label(&label, &scheme), label);
+
+    // Does not reach self
+    let label = label_from_u64(0b101_011);
+    assert_eq!(canonical_label(&label, &scheme), label);
+}
+
+/// An error returned by `convert_label`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConvertError {
+    /// No form of the source scheme matches the first director.
+    NoMatchingForm,
+    /// No form of the destination scheme can encode this interface.
+    Unrepresentable(Director),
+    /// The converted label does not fit in 64 bits.
+    TooLong,
+    /// Nothing is left in the label after its first director, so it
+    /// does not end with a self-interface director on a director
+    /// boundary: the director is partial (it includes padding bits) or
+    /// the path has no end. Converting it would lose or invent hops.
+    Misaligned,
+}
+
+/// Re-encodes the first director of a label, from a form of the `from`
+/// scheme to the narrowest form of the `to` scheme which can represent
+/// its interface, like cjdns' `EncodingScheme_convertLabel`.
+/// The rest of the label is kept as it is.
+///
+/// A label whose first director is a self-interface director is
+/// returned unchanged, as `0001` means the self interface in all schemes.
+/// Otherwise, the rest of the label must not be empty, as the path has
+/// to end with a self-interface director (see `ConvertError::Misaligned`).
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let fixed = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+/// let variable = EncodingScheme::from_iter(vec![
+///     EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+///     EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+/// ].iter());
+/// // Interface 5, then self
+/// let label = convert_label(&label_from_u64(0b001_101), &fixed, &variable);
+/// assert_eq!(label, Ok(label_from_u64(0b001_101_1)));
+/// ```
+pub fn convert_label(label: &Label, from: &EncodingScheme, to: &EncodingScheme) -> Result<Label, ConvertError> {
+    let from_forms = from.clone().into_iter().collect::<Vec<_>>();
+    let mut to_forms = to.clone().into_iter().collect::<Vec<_>>();
+    let bits = u64_from_label(*label);
+    let form = match first_form(bits, &from_forms) {
+        Some(form) if form.prefix_length + form.bit_count < 64 => form,
+        _ => return Err(ConvertError::NoMatchingForm),
+    };
+    let (rest, director) = right_shift_collect(bits, form.prefix_length + form.bit_count);
+    if is_self_director(director) {
+        return Ok(*label)
+    }
+    if rest == 0 {
+        return Err(ConvertError::Misaligned)
+    }
+    let interface = director >> form.prefix_length;
+
+    to_forms.sort_by_key(|form| form.prefix_length + form.bit_count);
+    for form in to_forms.iter() {
+        if form.bit_count < 64 && interface >= (1u64 << form.bit_count) {
+            continue // Does not fit in this form
+        }
+        let director = (interface << form.prefix_length) | form.prefix;
+        if is_self_director(director) {
+            continue // Would be read as a self-interface director
+        }
+        let director_length = (form.prefix_length + form.bit_count) as u32;
+        if director_length >= 64 || (64 - rest.leading_zeros()) + director_length > 64 {
+            return Err(ConvertError::TooLong)
+        }
+        return Ok(label_from_u64((rest << director_length) | director))
+    }
+    Err(ConvertError::Unrepresentable(interface))
+}
+
+#[test]
+fn test_convert_label() {
+    use std::iter::FromIterator;
+    let fixed = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+    let variable = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+    ].iter());
+
+    // Interfaces 5 and 3, then self: only the first director changes.
+    let label = label_from_u64(0b001_011_101);
+    let converted = convert_label(&label, &fixed, &variable).unwrap();
+    assert_eq!(converted, label_from_u64(0b001_011_101_1));
+    // And back
+    assert_eq!(convert_label(&converted, &variable, &fixed), Ok(label));
+
+    // Interface 20 needs the second form
+    let label = label_from_u64(0b0001_10100_10);
+    assert_eq!(convert_label(&label, &variable, &variable), Ok(label));
+    assert_eq!(convert_label(&label, &variable, &fixed), Err(ConvertError::Unrepresentable(20)));
+
+    // Self route
+    assert_eq!(convert_label(&SELF_LABEL, &fixed, &variable), Ok(SELF_LABEL));
+
+    // No form of `from` matches the first director
+    assert_eq!(convert_label(&label_from_u64(0b0001_00), &variable, &fixed), Err(ConvertError::NoMatchingForm));
+
+    // Wider director at the front of a full label
+    let label = label_from_u64(0b1 << 63 | 0b101);
+    assert_eq!(convert_label(&label, &fixed, &variable), Err(ConvertError::TooLong));
+
+    // The 5-bit director of interface 20 is cut short by the end of
+    // the label: its highest bits are padding.
+    let label = label_from_u64(0b100_10);
+    assert_eq!(convert_label(&label, &variable, &fixed), Err(ConvertError::Misaligned));
+    // Interface 5, with no self-interface director after it.
+    assert_eq!(convert_label(&label_from_u64(0b101), &fixed, &variable), Err(ConvertError::Misaligned));
+}
+
+#[test]
+fn test_convert_label_canonical_schemes() {
+    use std::iter::FromIterator;
+    // Schemes defined in cjdns' switch/EncodingScheme.h
+    let fixed4 = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 4, prefix_length: 0 }].iter());
+    let fixed8 = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 8, prefix_length: 0 }].iter());
+    let v48 = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b1, bit_count: 4, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b0, bit_count: 8, prefix_length: 1 },
+    ].iter());
+    let v358 = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+
+    // Interface 5, then self
+    assert_eq!(convert_label(&label_from_u64(0b0001_0101), &fixed4, &v358), Ok(label_from_u64(0b0001_101_1)));
+    assert_eq!(convert_label(&label_from_u64(0b0001_101_1), &v358, &fixed4), Ok(label_from_u64(0b0001_0101)));
+    assert_eq!(convert_label(&label_from_u64(0b0001_0101), &fixed4, &v48), Ok(label_from_u64(0b0001_0101_1)));
+
+    // Interface 200 only fits in the 8-bit forms
+    assert_eq!(convert_label(&label_from_u64(0b0001_11001000), &fixed8, &v358), Ok(label_from_u64(0b0001_11001000_00)));
+    assert_eq!(convert_label(&label_from_u64(0b0001_11001000), &fixed8, &v48), Ok(label_from_u64(0b0001_11001000_0)));
+    assert_eq!(convert_label(&label_from_u64(0b0001_11001000_00), &v358, &fixed4), Err(ConvertError::Unrepresentable(200)));
+
+    // Interface 0 would be read as the self interface in the 3-bit
+    // form, so it takes the 5-bit one.
+    assert_eq!(convert_label(&label_from_u64(0b0001_0000), &fixed4, &v358), Ok(label_from_u64(0b0001_00000_10)));
+    assert_eq!(convert_label(&label_from_u64(0b0001_00000_10), &v358, &fixed4), Ok(label_from_u64(0b0001_0000)));
+}
+
 const BYTE_REVERSE_TABLE: [u8; 256] = [
         0x00, 0x80, 0x40, 0xc0, 0x20, 0xa0, 0x60, 0xe0,
         0x10, 0x90, 0x50, 0xd0, 0x30, 0xb0, 0x70, 0xf0,
@@ -180,7 +961,20 @@ const BYTE_REVERSE_TABLE: [u8; 256] = [
 /// println!("{:b}", u64_from_label(label));
 /// assert_eq!(0b010110_110101_1000_000000000000000000000000000_0000001_10011_110011011, u64_from_label(label));
 /// ```
+///
+/// The self route is its own reverse: `SELF_LABEL` is left unchanged
+/// instead of being turned into a label whose only bit is the highest
+/// one.
+///
+/// All 64 bits are reversed at once, without decoding directors: this
+/// turns the return path accumulated by `switch` (in which each
+/// director is written bit-reversed, whatever its width) into a path.
+/// It does not reverse the order of the hops of a path; see
+/// `reverse_label_with_scheme` for that.
 pub fn reverse_label(label: &mut Label) {
+    if *label == SELF_LABEL {
+        return
+    }
     /* TODO: compare performance with non-inplace version:
     let mut new_label = [0u8; 8];
     for i in 0..8 {
@@ -195,3 +989,301 @@ pub fn reverse_label(label: &mut Label) {
     }
 }
 
+#[test]
+fn test_reverse_self_label() {
+    let mut label = SELF_LABEL;
+    reverse_label(&mut label);
+    assert_eq!(label, SELF_LABEL);
+
+    let mut label = WILDCARD_LABEL;
+    reverse_label(&mut label);
+    assert_eq!(label, WILDCARD_LABEL);
+
+    // Other labels are still bit-reversed.
+    let mut label = label_from_u64(0b0001_011);
+    reverse_label(&mut label);
+    assert_eq!(u64_from_label(label), 0b110_1000 << 57);
+}
+
+#[test]
+fn test_reverse_consumed_label() {
+    // A label whose path was fully consumed only holds the return
+    // path, which reverses into a path back to the origin.
+    let (mut label, decision) = switch(&SELF_LABEL, 4, &0b1100);
+    assert_eq!(decision, RoutingDecision::SelfInterface(0b0001));
+    assert_eq!(u64_from_label(label), 0b1100 << 60);
+    reverse_label(&mut label);
+    assert_eq!(u64_from_label(label), 0b0011);
+
+    // Reversing twice gives the original label back.
+    reverse_label(&mut label);
+    assert_eq!(u64_from_label(label), 0b1100 << 60);
+}
+
+/// Reverses the order of the hops of a path, decoding its directors with
+/// `scheme` (which is assumed to be the scheme of every switch on the
+/// path): the directors `d1, d2, ..., dn` followed by a self-interface
+/// director become `dn, ..., d2, d1` followed by the same self-interface
+/// director. Each director keeps its form and its bits.
+///
+/// Returns false, and leaves the label unchanged, if it does not decode
+/// cleanly with the scheme (see `validate_route`).
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let variable = EncodingScheme::from_iter(vec![
+///     EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+///     EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+/// ].iter());
+/// // Interface 20, then interface 5
+/// let mut label = label_from_u64(0b0001_101_1_10100_10);
+/// assert!(reverse_label_with_scheme(&mut label, &variable));
+/// assert_eq!(label, label_from_u64(0b0001_10100_10_101_1));
+/// ```
+pub fn reverse_label_with_scheme(label: &mut Label, scheme: &EncodingScheme) -> bool {
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    let mut bits = u64_from_label(*label);
+    let mut directors = Vec::new();
+    let mut remaining_bits = 64;
+    while bits != 0 {
+        let (director, director_length) = match first_director(bits, &forms) {
+            Some(director) => director,
+            None => return false,
+        };
+        if director_length > remaining_bits {
+            return false
+        }
+        if is_self_director(director) {
+            let mut reversed = director;
+            for &(director, director_length) in directors.iter() {
+                reversed = (reversed << director_length) | director;
+            }
+            *label = label_from_u64(reversed);
+            return true
+        }
+        directors.push((director, director_length));
+        bits >>= director_length;
+        remaining_bits -= director_length;
+    }
+    false
+}
+
+#[test]
+fn test_reverse_label_with_scheme() {
+    use std::iter::FromIterator;
+    let v358 = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+
+    // Interfaces 7, 21 and 85, then self.
+    let path = label_from_u64(0b0001_01010101_00_10101_10_111_1);
+    let mut label = path;
+    assert!(reverse_label_with_scheme(&mut label, &v358));
+    assert_eq!(label, label_from_u64(0b0001_111_1_10101_10_01010101_00));
+    assert_eq!(hops_to_self(&label, &v358), 3);
+    // Reversing twice gives the path back.
+    assert!(reverse_label_with_scheme(&mut label, &v358));
+    assert_eq!(label, path);
+
+    // Reversing all the bits does not even give a route.
+    let mut naive = path;
+    reverse_label(&mut naive);
+    assert!(!validate_route(&naive, &v358));
+
+    let mut label = SELF_LABEL;
+    assert!(reverse_label_with_scheme(&mut label, &v358));
+    assert_eq!(label, SELF_LABEL);
+
+    // No self-interface director
+    let mut label = label_from_u64(0b10101_10_111_1);
+    assert!(!reverse_label_with_scheme(&mut label, &v358));
+    assert_eq!(label, label_from_u64(0b10101_10_111_1));
+    let mut label = WILDCARD_LABEL;
+    assert!(!reverse_label_with_scheme(&mut label, &v358));
+}
+
+/// An error returned by `splice`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SpliceError {
+    /// One of the labels is `WILDCARD_LABEL`, which is not a path.
+    Wildcard,
+    /// The spliced label would not fit in 64 bits (cjdns keeps 4 bits
+    /// of margin, so it must fit in 60 bits).
+    TooLong,
+}
+
+/// Splices two paths into a single one, like cjdns' `LabelSplicer_splice`:
+/// `outer` is the label to reach a node A, and `inner` the label of an
+/// other node, relative to A. The self-interface director terminating
+/// `outer` is replaced with `inner`, which gives the label of the other
+/// node relative to the origin of `outer`.
+///
+/// ```
+/// # use fcp_switching::operation::*;
+/// let spliced = splice(&label_from_u64(0x15), &label_from_u64(0x13)).unwrap();
+/// assert_eq!(u64_from_label(spliced), 0x153);
+/// ```
+pub fn splice(inner: &Label, outer: &Label) -> Result<Label, SpliceError> {
+    let inner = u64_from_label(*inner);
+    let outer = u64_from_label(*outer);
+    if inner == 0 || outer == 0 {
+        return Err(SpliceError::Wildcard)
+    }
+    let outer_length = 63 - outer.leading_zeros();
+    if (63 - inner.leading_zeros()) + outer_length > 59 {
+        return Err(SpliceError::TooLong)
+    }
+    Ok(label_from_u64(((inner ^ 1) << outer_length) ^ outer))
+}
+
+#[test]
+fn test_splice() {
+    // cjdns' LabelSplicer vector, and a two-hop path with 3-bit directors.
+    assert_eq!(splice(&label_from_u64(0x15), &label_from_u64(0x13)), Ok(label_from_u64(0x153)));
+    assert_eq!(splice(&label_from_u64(0b0001_101), &label_from_u64(0b0001_011)), Ok(label_from_u64(0b0001_101_011)));
+
+    // Splicing the self route is a no-op.
+    assert_eq!(splice(&SELF_LABEL, &label_from_u64(0x13)), Ok(label_from_u64(0x13)));
+    assert_eq!(splice(&label_from_u64(0x15), &SELF_LABEL), Ok(label_from_u64(0x15)));
+
+    assert_eq!(splice(&WILDCARD_LABEL, &label_from_u64(0x13)), Err(SpliceError::Wildcard));
+    assert_eq!(splice(&label_from_u64(0b1 << 55), &label_from_u64(0b1 << 4)), Ok(label_from_u64(0b1 << 59)));
+    assert_eq!(splice(&label_from_u64(0b1 << 55), &label_from_u64(0b1 << 5)), Err(SpliceError::TooLong));
+}
+
+/// An error returned by `director_length`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DirectorError {
+    /// The label is `WILDCARD_LABEL`, which has no director.
+    Wildcard,
+    /// No form of the scheme matches the low bits of the label.
+    NoMatchingForm,
+}
+
+/// Returns the width (prefix included) of the first director of a label,
+/// ie. the number of bits a switch using `scheme` consumes from it, found
+/// with the form whose prefix matches the low bits of the label.
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let variable = EncodingScheme::from_iter(vec![
+///     EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+///     EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+/// ].iter());
+/// assert_eq!(director_length(&label_from_u64(0b0001_101_1), &variable), Ok(4));
+/// assert_eq!(director_length(&label_from_u64(0b0001_10100_10), &variable), Ok(7));
+/// ```
+pub fn director_length(label: &Label, scheme: &EncodingScheme) -> Result<u8, DirectorError> {
+    if *label == WILDCARD_LABEL {
+        return Err(DirectorError::Wildcard)
+    }
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    match first_form(u64_from_label(*label), &forms) {
+        Some(form) => Ok(form.prefix_length + form.bit_count),
+        None => Err(DirectorError::NoMatchingForm),
+    }
+}
+
+#[test]
+fn test_director_length() {
+    use std::iter::FromIterator;
+    let fixed = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+    assert_eq!(director_length(&label_from_u64(0b0001_011), &fixed), Ok(3));
+    assert_eq!(director_length(&SELF_LABEL, &fixed), Ok(3));
+    assert_eq!(director_length(&WILDCARD_LABEL, &fixed), Err(DirectorError::Wildcard));
+
+    let v358 = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+    assert_eq!(director_length(&label_from_u64(0b0001_101_1), &v358), Ok(4));
+    assert_eq!(director_length(&label_from_u64(0b0001_10100_10), &v358), Ok(7));
+    assert_eq!(director_length(&label_from_u64(0b0001_10100000_00), &v358), Ok(10));
+    assert_eq!(director_length(&SELF_LABEL, &v358), Ok(4));
+
+    let v35 = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+    ].iter());
+    assert_eq!(director_length(&label_from_u64(0b0001_10100000_00), &v35), Err(DirectorError::NoMatchingForm));
+}
+
+/// Returns how many bits of a label its path uses when decoded with
+/// `scheme`: the widths (prefixes included) of its directors, up to and
+/// including the self-interface director. `64 - bit_length` is the room
+/// left to splice it behind another path.
+///
+/// Unlike `remaining_bits`, this counts the leading zeros of the
+/// self-interface director.
+///
+/// Returns None if the label does not decode cleanly with `scheme`
+/// (see `validate_route`).
+///
+/// # Examples
+///
+/// ```
+/// # use std::iter::FromIterator;
+/// # use fcp_switching::operation::*;
+/// # use fcp_switching::encoding_scheme::*;
+/// let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+/// assert_eq!(bit_length(&label_from_u64(0b001_101_011), &scheme), Some(9));
+/// assert_eq!(remaining_bits(&label_from_u64(0b001_101_011)), 7);
+/// ```
+pub fn bit_length(label: &Label, scheme: &EncodingScheme) -> Option<u8> {
+    let forms = scheme.clone().into_iter().collect::<Vec<_>>();
+    let mut bits = u64_from_label(*label);
+    let mut length = 0;
+    while bits != 0 {
+        let (director, director_length) = match first_director(bits, &forms) {
+            Some(director) => director,
+            None => return None,
+        };
+        length += director_length;
+        if length > 64 {
+            return None
+        }
+        if is_self_director(director) {
+            return Some(length)
+        }
+        bits >>= director_length;
+    }
+    None
+}
+
+#[test]
+fn test_bit_length() {
+    use std::iter::FromIterator;
+    let fixed = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 4, prefix_length: 0 }].iter());
+    assert_eq!(bit_length(&SELF_LABEL, &fixed), Some(4));
+    let one_hop = label_from_u64(0b0001_0011);
+    let three_hops = label_from_u64(0b0001_0110_1010_0011);
+    assert_eq!(bit_length(&one_hop, &fixed), Some(8));
+    assert_eq!(bit_length(&three_hops, &fixed), Some(16));
+    assert_eq!(bit_length(&three_hops, &fixed).unwrap() - bit_length(&one_hop, &fixed).unwrap(), 8);
+
+    let v358 = EncodingScheme::from_iter([
+        EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+        EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        EncodingSchemeForm { prefix: 0b00, bit_count: 8, prefix_length: 2 },
+    ].iter());
+    let one_hop = label_from_u64(0b0001_101_1);
+    let three_hops = label_from_u64(0b0001_01010101_00_10101_10_011_1);
+    assert_eq!(bit_length(&one_hop, &v358), Some(8));
+    assert_eq!(bit_length(&three_hops, &v358), Some(25));
+
+    // Labels which do not reach a self-interface director.
+    assert_eq!(bit_length(&WILDCARD_LABEL, &fixed), None);
+    assert_eq!(bit_length(&label_from_u64(0b0011), &fixed), None);
+    assert_eq!(bit_length(&EXHAUSTED_LABEL, &fixed), None);
+}
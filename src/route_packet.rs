@@ -2,20 +2,34 @@
 //! a packet exchanged by switches and routers to advertise routes.
 
 use std::collections::HashMap;
+use std::fmt;
+use std::error;
 use std::string::FromUtf8Error;
 use std::hash::{Hash, Hasher};
 use std::cmp::Ordering;
+use std::time::{Duration, Instant};
 
 use simple_bencode;
 use simple_bencode::Value as BValue;
 use simple_bencode::decoding_helpers::HelperDecodeError;
 
-use encoding_scheme::EncodingScheme;
-use operation::Label;
+use fcp_cryptoauth::wrapper::PublicKey;
+
+use encoding_scheme::{EncodingScheme, EncodingSchemeForm};
+use operation::{Label, validate_route, director_length};
 
 const PUBLIC_KEY_LENGTH: usize = 32;
+/// Length of the `target_address` of `fn` queries: an IPv6 address.
+pub const TARGET_ADDRESS_LENGTH: usize = 16;
 const PATH_LENGTH: usize = 8;
 
+/// Returns the bytes of a public key, as stored in `NodeData`.
+pub fn key_bytes(pk: &PublicKey) -> [u8; PUBLIC_KEY_LENGTH] {
+    let mut bytes = [0u8; PUBLIC_KEY_LENGTH];
+    bytes.copy_from_slice(&pk.0[..PUBLIC_KEY_LENGTH]);
+    bytes
+}
+
 /// Represents a cjdns node, with its public key, path through the network,
 /// and protocol version.
 #[derive(Debug, Clone)]
@@ -50,6 +64,50 @@ impl Hash for NodeData {
     }
 }
 
+/// Types of queries of a `RoutePacket`, stored as a string in its
+/// `query` field.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum RouteQuery {
+    /// `fn`: find the nodes closest to `target_address`, which is
+    /// required (see `RoutePacket::is_find_node`).
+    FindNode,
+    /// `gp`: get the peers of a node.
+    GetPeers,
+    /// `pn`: route-level ping, used to test end-to-end reachability.
+    /// Answered with a response echoing the transaction id.
+    Ping,
+    /// `ka`: keepalive, telling the session is still in use (see
+    /// `switch_core::SwitchConfig::session_ttl`). Not answered.
+    /// This is not a cjdns query: cjdns routers do not send it, so their
+    /// sessions are only kept open by their other messages.
+    Keepalive,
+    /// Any other query.
+    Other(String),
+}
+
+impl RouteQuery {
+    pub fn new(query: &str) -> RouteQuery {
+        match query {
+            "fn" => RouteQuery::FindNode,
+            "gp" => RouteQuery::GetPeers,
+            "pn" => RouteQuery::Ping,
+            "ka" => RouteQuery::Keepalive,
+            _ => RouteQuery::Other(query.to_owned()),
+        }
+    }
+
+    /// Returns the string used for this query in `RoutePacket::query`.
+    pub fn as_str(&self) -> &str {
+        match *self {
+            RouteQuery::FindNode => "fn",
+            RouteQuery::GetPeers => "gp",
+            RouteQuery::Ping => "pn",
+            RouteQuery::Keepalive => "ka",
+            RouteQuery::Other(ref query) => query,
+        }
+    }
+}
+
 /// A packet exchanged by switches and routers to advertise routes.
 ///
 /// Described here: https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#the-router
@@ -58,8 +116,11 @@ pub struct RoutePacket {
     /// The type of query. May be absent (a response), `fn` (find node),
     /// `gp` (get peers), `pn` (ping node), etc.
     pub query: Option<String>,
-    /// The index Encoding Scheme Form in `encoding_scheme` used for
-    /// sending this route packet.
+    /// The index, in the emitter's `encoding_scheme`, of the form of the
+    /// director the emitter uses for the interface this packet was
+    /// received on. It is applied from the far end: it tells how the
+    /// emitter encodes the hop back to us, not how we reach it.
+    /// Use `RoutePacket::encoding_form` to read it.
     pub encoding_index: Option<i64>,
     /// The Encoding Scheme of the emitted. See
     /// https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#encoding-schemes
@@ -77,35 +138,230 @@ pub struct RoutePacket {
     pub transaction_id: Vec<u8>,
     /// The protocol version of the emitter. Maps to the cjdns version.
     pub protocol_version: i64,
+    /// The reach of the path to the emitter, as estimated by the emitter.
+    /// Sent to and by supernodes, along with `received_time`.
+    pub reach: Option<i64>,
+    /// When the emitter received the packet this one answers to
+    /// (`recvTime`, in milliseconds). Sent to and by supernodes, along
+    /// with `reach`.
+    pub received_time: Option<i64>,
+    /// Public keys of the nodes this packet went through (`asPath`),
+    /// concatenated, as a path vector. Sent by some cjdns variants for
+    /// loop-free routing; use `RoutePacket::path_contains` to read it.
+    pub as_path: Option<Vec<u8>>,
+    /// Set to 1 in responses to `gp` queries when the emitter had more
+    /// nodes than fit in the packet. Use `RoutePacket::has_more` to read it.
+    pub more: Option<i64>,
 }
 
 /// An error returned by `RoutePacket::decode`
-pub enum DecodeError {
+#[derive(Debug)]
+pub enum RoutePacketError {
     BencodeDecodeError(simple_bencode::DecodeError),
     BadType(String),
     MissingKey(String),
     UnicodeDecodeError(FromUtf8Error),
+    /// The encoding index (`ei`) does not reference a form of the
+    /// encoding scheme (`es`).
+    EncodingIndexOutOfRange(i64),
+}
+
+impl fmt::Display for RoutePacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RoutePacketError::BencodeDecodeError(ref e) => write!(f, "bad bencode: {:?}", e),
+            RoutePacketError::BadType(ref key) => write!(f, "bad type for key {}", key),
+            RoutePacketError::MissingKey(ref key) => write!(f, "missing key {}", key),
+            RoutePacketError::UnicodeDecodeError(ref e) => write!(f, "bad UTF-8: {}", e),
+            RoutePacketError::EncodingIndexOutOfRange(ei) => write!(f, "encoding index {} out of range", ei),
+        }
+    }
+}
+
+impl error::Error for RoutePacketError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RoutePacketError::UnicodeDecodeError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HelperDecodeError> for RoutePacketError {
+    fn from(e: HelperDecodeError) -> RoutePacketError {
+        match e {
+            HelperDecodeError::BencodeDecodeError(e) => RoutePacketError::BencodeDecodeError(e),
+            HelperDecodeError::BadType(s) => RoutePacketError::BadType(s),
+            HelperDecodeError::MissingKey(s) => RoutePacketError::MissingKey(s),
+            HelperDecodeError::FromUtf8Error(e) => RoutePacketError::UnicodeDecodeError(e),
+        }
+    }
+}
+
+/// Decodes a bencoded value, like `simple_bencode::decode`, which (as
+/// of 0.1.4) does not consume the `e` closing a list: the values
+/// following a list in a dictionary (eg. `np`, `p` and `txid` after
+/// a list-form `n`) are lost.
+///
+/// Data after the value is ignored, as `simple_bencode::decode` does.
+fn bdecode(v: &[u8]) -> Result<BValue, simple_bencode::DecodeError> {
+    let mut pos = 0;
+    bdecode_value(v, &mut pos)
+}
+
+fn bdecode_next(v: &[u8], pos: &mut usize) -> Result<u8, simple_bencode::DecodeError> {
+    match v.get(*pos) {
+        Some(&byte) => {
+            *pos += 1;
+            Ok(byte)
+        },
+        None => Err(simple_bencode::DecodeError::UnexpectedEndOfBuffer),
+    }
+}
+
+fn bdecode_value(v: &[u8], pos: &mut usize) -> Result<BValue, simple_bencode::DecodeError> {
+    let first_byte = try!(bdecode_next(v, pos));
+    match first_byte {
+        b'i' => bdecode_integer(v, pos).map(BValue::Integer),
+        b'l' => {
+            let mut items = Vec::new();
+            loop {
+                match v.get(*pos) {
+                    Some(&b'e') => {
+                        *pos += 1;
+                        break
+                    },
+                    Some(_) => items.push(try!(bdecode_value(v, pos))),
+                    None => return Err(simple_bencode::DecodeError::UnexpectedEndOfBuffer),
+                }
+            }
+            Ok(BValue::List(items))
+        },
+        b'd' => {
+            let mut map = HashMap::new();
+            loop {
+                match try!(bdecode_next(v, pos)) {
+                    b'e' => break,
+                    byte @ b'0' ..= b'9' => {
+                        let key = try!(bdecode_string(v, pos, byte));
+                        let value = try!(bdecode_value(v, pos));
+                        map.insert(key, value);
+                    },
+                    byte => return Err(simple_bencode::DecodeError::UnexpectedCharacter(format!("'{}' while expecting 'e' or '0'..'9' (next key in dict)", byte as char))),
+                }
+            }
+            Ok(BValue::Dictionary(map))
+        },
+        b'0' ..= b'9' => bdecode_string(v, pos, first_byte).map(BValue::String),
+        byte => Err(simple_bencode::DecodeError::UnexpectedCharacter(format!("'{}' instead of the first byte of an object.", byte as char))),
+    }
+}
+
+fn bdecode_integer(v: &[u8], pos: &mut usize) -> Result<i64, simple_bencode::DecodeError> {
+    let negative = v.get(*pos) == Some(&b'-');
+    if negative {
+        *pos += 1;
+    }
+    let mut res = 0i64;
+    loop {
+        let digit = try!(bdecode_next(v, pos));
+        match digit {
+            b'e' => break,
+            b'0' ..= b'9' => {
+                res = match res.checked_mul(10).and_then(|res| res.checked_add((digit - b'0') as i64)) {
+                    Some(res) => res,
+                    None => return Err(simple_bencode::DecodeError::UnexpectedCharacter("Integer overflow.".to_owned())),
+                }
+            },
+            _ => return Err(simple_bencode::DecodeError::UnexpectedCharacter(format!("'{}' while reading an integer.", digit as char))),
+        }
+    }
+    Ok(if negative { -res } else { res })
+}
+
+fn bdecode_string(v: &[u8], pos: &mut usize, first_digit: u8) -> Result<Vec<u8>, simple_bencode::DecodeError> {
+    let mut length = (first_digit - b'0') as usize;
+    loop {
+        let digit = try!(bdecode_next(v, pos));
+        match digit {
+            b':' => break,
+            b'0' ..= b'9' => {
+                length = match length.checked_mul(10).and_then(|length| length.checked_add((digit - b'0') as usize)) {
+                    Some(length) => length,
+                    None => return Err(simple_bencode::DecodeError::UnexpectedEndOfBuffer),
+                }
+            },
+            _ => return Err(simple_bencode::DecodeError::UnexpectedCharacter(format!("'{}' while reading a string length", digit as char))),
+        }
+    }
+    if v.len() - *pos < length {
+        return Err(simple_bencode::DecodeError::UnexpectedEndOfBuffer)
+    }
+    let string = v[*pos..*pos+length].to_vec();
+    *pos += length;
+    Ok(string)
+}
+
+/// Pops the node list (`n`) from a decoded route packet. It is usually
+/// a compact byte string (concatenated public keys and paths), but some
+/// versions send a list of byte strings, one per node; they are
+/// concatenated to the compact form.
+fn pop_nodes(map: &mut HashMap<Vec<u8>, BValue>) -> Result<Option<Vec<u8>>, RoutePacketError> {
+    match map.remove(&b"n".to_vec()) {
+        None => Ok(None),
+        Some(BValue::String(nodes)) => Ok(Some(nodes)),
+        Some(BValue::List(items)) => {
+            let mut nodes = Vec::new();
+            for item in items {
+                match item {
+                    BValue::String(ref node) if node.len() == PUBLIC_KEY_LENGTH+PATH_LENGTH => nodes.extend_from_slice(node),
+                    v => return Err(RoutePacketError::BadType(format!("Expected node string in 'n', got: {:?}", v))),
+                }
+            }
+            Ok(Some(nodes))
+        },
+        Some(v) => Err(RoutePacketError::BadType(format!("Expected string or list for 'n', got: {:?}", v))),
+    }
+}
+
+/// Deserialize a batch of `RoutePacket`s (eg. from captured traffic).
+///
+/// A packet failing to decode does not prevent the others from being
+/// decoded; the result for each packet is returned in the same order.
+pub fn decode_all(packets: &[Vec<u8>]) -> Vec<Result<RoutePacket, RoutePacketError>> {
+    packets.iter().map(|packet| RoutePacket::decode(packet)).collect()
 }
 
 impl RoutePacket {
     /// Deserialize a `RoutePacket` from its bencoded representation.
-    pub fn decode(v: &[u8]) -> Result<RoutePacket, HelperDecodeError> {
-        let bvalue = simple_bencode::decode(v);
+    pub fn decode(v: &[u8]) -> Result<RoutePacket, RoutePacketError> {
+        let bvalue = bdecode(v);
         let mut map = match bvalue {
             Ok(BValue::Dictionary(map)) => map,
-            Ok(v) => return Err(HelperDecodeError::BadType(format!("Expected dict at root, got: {:?}", v))),
-            Err(e) => return Err(HelperDecodeError::BencodeDecodeError(e)),
+            Ok(v) => return Err(RoutePacketError::BadType(format!("Expected dict at root, got: {:?}", v))),
+            Err(e) => return Err(RoutePacketError::BencodeDecodeError(e)),
         };
         //println!("{:?}", map);
         //println!("{:?}", map.keys().collect::<Vec<_>>().into_iter().map(|v| String::from_utf8(v.clone()).unwrap()).collect::<Vec<String>>()); // DEBUG: to show the keys in the messages
         let query = try!(simple_bencode::decoding_helpers::pop_value_utf8_string_option(&mut map, "q".to_owned()));
         let encoding_index = try!(simple_bencode::decoding_helpers::pop_value_integer_option(&mut map, "ei".to_owned()));
         let encoding_scheme = try!(simple_bencode::decoding_helpers::pop_value_bytestring_option(&mut map, "es".to_owned())).map(EncodingScheme::new);
-        let nodes = try!(simple_bencode::decoding_helpers::pop_value_bytestring_option(&mut map, "n".to_owned()));
+        let nodes = try!(pop_nodes(&mut map));
         let node_protocol_versions = try!(simple_bencode::decoding_helpers::pop_value_bytestring_option(&mut map, "np".to_owned()));
         let target_address = try!(simple_bencode::decoding_helpers::pop_value_bytestring_option(&mut map, "tar".to_owned()));
         let transaction_id = try!(simple_bencode::decoding_helpers::pop_value_bytestring(&mut map, "txid".to_owned()));
         let protocol_version = try!(simple_bencode::decoding_helpers::pop_value_integer(&mut map, "p".to_owned()));
+        let reach = try!(simple_bencode::decoding_helpers::pop_value_integer_option(&mut map, "reach".to_owned()));
+        let received_time = try!(simple_bencode::decoding_helpers::pop_value_integer_option(&mut map, "recvTime".to_owned()));
+        let as_path = try!(simple_bencode::decoding_helpers::pop_value_bytestring_option(&mut map, "asPath".to_owned()));
+        let more = try!(simple_bencode::decoding_helpers::pop_value_integer_option(&mut map, "more".to_owned()));
+        if let (Some(ei), Some(ref es)) = (encoding_index, &encoding_scheme) {
+            // Labels toward the emitter are built using this form, so
+            // it has to exist.
+            if ei < 0 || ei as usize >= es.form_count() {
+                return Err(RoutePacketError::EncodingIndexOutOfRange(ei));
+            }
+        }
         Ok(RoutePacket {
             query: query,
             encoding_index: encoding_index,
@@ -115,9 +371,145 @@ impl RoutePacket {
             target_address: target_address,
             transaction_id: transaction_id,
             protocol_version: protocol_version,
+            reach: reach,
+            received_time: received_time,
+            as_path: as_path,
+            more: more,
             })
     }
 
+    /// Returns `encoding_index` as an index into `encoding_scheme`, or
+    /// None if either is missing or the index does not reference a form
+    /// of the scheme.
+    pub fn checked_encoding_index(&self) -> Option<usize> {
+        match (self.encoding_index, &self.encoding_scheme) {
+            (Some(ei), &Some(ref es)) if ei >= 0 && (ei as usize) < es.form_count() => Some(ei as usize),
+            _ => None,
+        }
+    }
+
+    /// Returns the form of the emitter's scheme referenced by
+    /// `encoding_index`, ie. the form of the emitter's director back
+    /// toward us.
+    pub fn encoding_form(&self) -> Option<EncodingSchemeForm> {
+        let ei = match self.checked_encoding_index() {
+            Some(ei) => ei,
+            None => return None,
+        };
+        self.encoding_scheme.clone().and_then(|es| es.into_iter().nth(ei))
+    }
+
+    /// Returns whether the node with the given public key is listed in
+    /// the path vector (`as_path`), ie. whether this packet already went
+    /// through it. A node receiving a packet listing itself should drop it.
+    pub fn path_contains(&self, public_key: &[u8; PUBLIC_KEY_LENGTH]) -> bool {
+        match self.as_path {
+            Some(ref as_path) => as_path.chunks(PUBLIC_KEY_LENGTH).any(|key| key == &public_key[..]),
+            None => false,
+        }
+    }
+
+    /// Returns whether the emitter had more nodes than it sent (`more`).
+    pub fn has_more(&self) -> bool {
+        self.more.map_or(false, |more| more != 0)
+    }
+
+    /// Returns whether the paths of the nodes (`n`) are consistent with
+    /// the emitter's encoding scheme (`es`), ie. whether their first
+    /// director, the one the emitter's switch consumes, matches a form
+    /// of it.
+    ///
+    /// The rest of the paths is not checked: it goes through other
+    /// switches, which may use other schemes.
+    ///
+    /// True if the packet has no scheme or no nodes; false if the nodes
+    /// cannot be parsed.
+    pub fn nodes_match_scheme(&self) -> bool {
+        let scheme = match self.encoding_scheme {
+            Some(ref scheme) => scheme,
+            None => return true,
+        };
+        if self.nodes.is_none() {
+            return true
+        }
+        match self.read_nodes() {
+            Ok(nodes) => nodes.iter().all(|node| director_length(&node.path, scheme).is_ok()),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the type of query of this packet, or None if it is
+    /// a response.
+    pub fn route_query(&self) -> Option<RouteQuery> {
+        self.query.as_ref().map(|query| RouteQuery::new(query))
+    }
+
+    /// Returns whether this is a `fn` (find node) query.
+    ///
+    /// Such queries must have a `target_address` of
+    /// `TARGET_ADDRESS_LENGTH` bytes: the address of the node searched.
+    /// Use `RoutePacket::find_node_target` to get it.
+    pub fn is_find_node(&self) -> bool {
+        self.route_query() == Some(RouteQuery::FindNode)
+    }
+
+    /// Returns the target address of a `fn` query, or None if this is
+    /// not a `fn` query or its target address is missing or does not
+    /// have `TARGET_ADDRESS_LENGTH` bytes.
+    pub fn find_node_target(&self) -> Option<&[u8]> {
+        if !self.is_find_node() {
+            return None
+        }
+        match self.target_address {
+            Some(ref target) if target.len() == TARGET_ADDRESS_LENGTH => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Returns the protocol version (`p`) of the emitter, or None if it
+    /// is negative, which no cjdns version is.
+    pub fn protocol_version(&self) -> Option<u64> {
+        if self.protocol_version >= 0 {
+            Some(self.protocol_version as u64)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Returns a `gp` query, asking the recipient for its peers closest
+    /// to `target_address`.
+    pub fn get_peers(protocol_version: i64, transaction_id: Vec<u8>, target_address: Vec<u8>) -> RoutePacket {
+        RoutePacketBuilder::new(protocol_version, transaction_id)
+                .route_query(RouteQuery::GetPeers)
+                .target_address(target_address)
+                .finalize()
+    }
+
+    /// Returns a `fn` query, asking the recipient for the nodes it knows
+    /// closest to `target_address`, which should have
+    /// `TARGET_ADDRESS_LENGTH` bytes.
+    pub fn find_node(protocol_version: i64, transaction_id: Vec<u8>, target_address: Vec<u8>) -> RoutePacket {
+        RoutePacketBuilder::new(protocol_version, transaction_id)
+                .route_query(RouteQuery::FindNode)
+                .target_address(target_address)
+                .finalize()
+    }
+
+    /// Returns a `pn` query.
+    pub fn ping(protocol_version: i64, transaction_id: Vec<u8>) -> RoutePacket {
+        RoutePacketBuilder::new(protocol_version, transaction_id)
+                .route_query(RouteQuery::Ping)
+                .finalize()
+    }
+
+    /// Returns a `ka` query.
+    pub fn keepalive(protocol_version: i64, transaction_id: Vec<u8>) -> RoutePacket {
+        RoutePacketBuilder::new(protocol_version, transaction_id)
+                .route_query(RouteQuery::Keepalive)
+                .finalize()
+    }
+
     /// Deserialize a `RoutePacket` to its bencode representation.
     pub fn encode(self) -> Vec<u8> {
         let mut map = HashMap::new();
@@ -129,6 +521,10 @@ impl RoutePacket {
         self.target_address.map(|tar| map.insert(b"tar".to_vec(), BValue::String(tar)));
         map.insert(b"txid".to_vec(), BValue::String(self.transaction_id));
         map.insert(b"p".to_vec(), BValue::Integer(self.protocol_version));
+        self.reach.map(|reach| map.insert(b"reach".to_vec(), BValue::Integer(reach)));
+        self.received_time.map(|recv_time| map.insert(b"recvTime".to_vec(), BValue::Integer(recv_time)));
+        self.as_path.map(|as_path| map.insert(b"asPath".to_vec(), BValue::String(as_path)));
+        self.more.map(|more| map.insert(b"more".to_vec(), BValue::Integer(more)));
         simple_bencode::encode(&BValue::Dictionary(map))
     }
 
@@ -193,20 +589,253 @@ impl RoutePacket {
     }
 
     /// Writes `self.nodes` and `self.node_protocol_versions` together.
-    pub fn write_nodes(&mut self, nodes: Vec<NodeData>) {
-        assert!(nodes.iter().all(|n| n.version < 256));
-        let mut node_version_bytes = vec![1u8];
-        node_version_bytes.extend(nodes.iter().map(|n| n.version as u8));
+    ///
+    /// Returns an error and leaves `self` unchanged if a node cannot be
+    /// encoded (see `encode_nodes`).
+    pub fn write_nodes(&mut self, nodes: Vec<NodeData>) -> Result<(), String> {
+        let (node_bytes, node_version_bytes) = try!(encode_nodes(&nodes));
+        self.node_protocol_versions = Some(node_version_bytes);
+        self.nodes = Some(node_bytes);
+        Ok(())
+    }
+}
+
+/// The fields of a `RoutePacket`, decoded all at once into their typed
+/// forms (as returned by the accessors of `RoutePacket`), for consumers
+/// which need several of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePacketView {
+    /// See `RoutePacket::route_query`.
+    pub query: Option<RouteQuery>,
+    pub transaction_id: Vec<u8>,
+    /// See `RoutePacket::read_nodes`; None if the packet has no nodes.
+    pub nodes: Option<Vec<NodeData>>,
+    pub encoding_scheme: Option<EncodingScheme>,
+    /// See `RoutePacket::checked_encoding_index`.
+    pub encoding_index: Option<usize>,
+    /// See `RoutePacket::encoding_form`.
+    pub encoding_form: Option<EncodingSchemeForm>,
+    pub target_address: Option<Vec<u8>>,
+    pub protocol_version: i64,
+    pub reach: Option<i64>,
+    /// See `RoutePacket::has_more`.
+    pub more: bool,
+}
+
+impl RoutePacket {
+    /// Returns a `RoutePacketView` of this packet.
+    ///
+    /// Fails if the packet has nodes which cannot be parsed.
+    pub fn view(&self) -> Result<RoutePacketView, String> {
+        let nodes = match self.nodes {
+            Some(_) => Some(try!(self.read_nodes())),
+            None => None,
+        };
+        Ok(RoutePacketView {
+            query: self.route_query(),
+            transaction_id: self.transaction_id.clone(),
+            nodes: nodes,
+            encoding_scheme: self.encoding_scheme.clone(),
+            encoding_index: self.checked_encoding_index(),
+            encoding_form: self.encoding_form(),
+            target_address: self.target_address.clone(),
+            protocol_version: self.protocol_version,
+            reach: self.reach,
+            more: self.has_more(),
+        })
+    }
+}
+
+impl NodeData {
+    /// Returns whether the path of this node decodes cleanly with the
+    /// given encoding scheme (see `operation::validate_route`).
+    pub fn is_routable(&self, scheme: &EncodingScheme) -> bool {
+        validate_route(&self.path, scheme)
+    }
+}
 
-        let mut node_bytes = vec![0u8; nodes.len()*(PUBLIC_KEY_LENGTH+PATH_LENGTH)];
-        for (i, node) in nodes.iter().enumerate() {
-            let bytes_start = i*(PUBLIC_KEY_LENGTH+PATH_LENGTH);
-            node_bytes[bytes_start..bytes_start+PUBLIC_KEY_LENGTH].copy_from_slice(&node.public_key);
-            node_bytes[bytes_start+PUBLIC_KEY_LENGTH..bytes_start+PUBLIC_KEY_LENGTH+PATH_LENGTH].copy_from_slice(&node.path);
+/// Number of bytes used to encode each version in the node version list
+/// ('np') written by `encode_nodes`.
+const NODE_VERSION_LENGTH: usize = 1;
+
+/// Highest version of a node which fits in the node version list ('np')
+/// written by `encode_nodes`.
+pub const MAX_NODE_VERSION: u64 = (1 << (8*NODE_VERSION_LENGTH)) - 1;
+
+impl NodeData {
+    /// Appends the record of this node to the node list ('n') and its
+    /// version to the node version list ('np').
+    ///
+    /// Fails without writing anything if the version does not fit in
+    /// the version width of the list.
+    pub fn encode_into(&self, nodes: &mut Vec<u8>, versions: &mut Vec<u8>) -> Result<(), String> {
+        if self.version > MAX_NODE_VERSION {
+            return Err(format!("Node version {} does not fit in {} byte(s).", self.version, NODE_VERSION_LENGTH))
         }
+        nodes.extend_from_slice(&self.public_key);
+        nodes.extend_from_slice(&self.path);
+        versions.push(self.version as u8);
+        Ok(())
+    }
+}
 
-        self.node_protocol_versions = Some(node_version_bytes);
-        self.nodes = Some(node_bytes);
+/// Encodes a list of nodes as a node list ('n') and a node version
+/// list ('np'), in this order.
+///
+/// Public keys and paths always have the right length, as they are
+/// fixed-size arrays; but a node whose version does not fit in the version
+/// width is refused, instead of shifting all the records that follow it.
+pub fn encode_nodes(nodes: &[NodeData]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut node_bytes = Vec::with_capacity(nodes.len()*(PUBLIC_KEY_LENGTH+PATH_LENGTH));
+    let mut node_version_bytes = Vec::with_capacity(1+nodes.len()*NODE_VERSION_LENGTH);
+    node_version_bytes.push(NODE_VERSION_LENGTH as u8);
+    for node in nodes {
+        try!(node.encode_into(&mut node_bytes, &mut node_version_bytes));
+    }
+    Ok((node_bytes, node_version_bytes))
+}
+
+/// Returns a route packet a node can push to routers to announce itself,
+/// without waiting for a `gp` query: it has no query, and its nodes are
+/// only the announcing node, reachable through `my_label`, along with
+/// its encoding scheme.
+///
+/// Fails if `version` does not fit in the version width of nodes (see
+/// `MAX_NODE_VERSION`).
+pub fn build_self_announcement(my_key: &PublicKey, my_label: &Label, version: u64, scheme: &EncodingScheme) -> Result<RoutePacket, String> {
+    let myself = NodeData {
+        public_key: key_bytes(my_key),
+        path: *my_label,
+        version: version,
+    };
+    Ok(try!(RoutePacketBuilder::new(version as i64, Vec::new())
+            .nodes_vec(vec![myself]))
+            .encoding_scheme(scheme.clone())
+            .finalize())
+}
+
+/// A piece of an encoded route packet too large to fit in a single
+/// data packet, along with the header needed to put it back together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteFragment {
+    /// Transaction id of the route packet this is a piece of.
+    pub transaction_id: Vec<u8>,
+    /// Position of this fragment in the route packet, starting from 0.
+    pub index: u16,
+    /// Number of fragments the route packet was split into.
+    pub count: u16,
+    /// This fragment's slice of the encoded route packet.
+    pub data: Vec<u8>,
+}
+
+impl RouteFragment {
+    /// Encodes `packet` and splits it into fragments of at most
+    /// `max_length` bytes of data.
+    ///
+    /// Panics if `max_length` is 0 or if there would be more than
+    /// 65535 fragments.
+    pub fn split(packet: RoutePacket, max_length: usize) -> Vec<RouteFragment> {
+        assert!(max_length > 0);
+        let transaction_id = packet.transaction_id.clone();
+        let encoded = packet.encode();
+        let chunks = encoded.chunks(max_length).collect::<Vec<_>>();
+        assert!(chunks.len() <= u16::max_value() as usize);
+        let count = chunks.len() as u16;
+        chunks.into_iter().enumerate().map(|(index, chunk)| RouteFragment {
+            transaction_id: transaction_id.clone(),
+            index: index as u16,
+            count: count,
+            data: chunk.to_vec(),
+        }).collect()
+    }
+}
+
+/// Data of the fragments of a packet received so far, by index.
+type ReceivedFragments = Vec<Option<Vec<u8>>>;
+
+/// Largest number of fragments a `Reassembler` accepts for a packet.
+pub const MAX_FRAGMENT_COUNT: u16 = 64;
+
+/// Largest number of incomplete packets a `Reassembler` keeps fragments
+/// of.
+pub const MAX_PENDING_PACKETS: usize = 32;
+
+/// Puts route packets split into `RouteFragment`s back together,
+/// indexed by transaction id.
+pub struct Reassembler {
+    /// How long the fragments of an incomplete packet are kept.
+    timeout: Duration,
+    /// Fragments received so far, along with when the first of them
+    /// was received.
+    pending: HashMap<Vec<u8>, (Instant, ReceivedFragments)>,
+}
+
+impl Reassembler {
+    /// Returns a reassembler which gives up on a packet `timeout` after
+    /// receiving its first fragment (see `Reassembler::expire`).
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout: timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Adds a fragment received at `now`. Once all fragments of its
+    /// packet are received, returns the decoded packet.
+    ///
+    /// Fragments whose index is out of range, or whose count is greater
+    /// than `MAX_FRAGMENT_COUNT`, are ignored. A fragment whose count
+    /// does not match the previous fragments with the same transaction
+    /// id starts a new packet, replacing them; it still expires
+    /// `timeout` after the first fragment with this transaction id.
+    ///
+    /// Fragments of a new packet are ignored too if there are already
+    /// `MAX_PENDING_PACKETS` incomplete packets, once the expired ones
+    /// are dropped (see `Reassembler::expire`).
+    pub fn add_fragment(&mut self, fragment: RouteFragment, now: Instant) -> Option<Result<RoutePacket, RoutePacketError>> {
+        if fragment.index >= fragment.count || fragment.count > MAX_FRAGMENT_COUNT {
+            return None
+        }
+        if !self.pending.contains_key(&fragment.transaction_id) && self.pending.len() >= MAX_PENDING_PACKETS {
+            self.expire(now);
+            if self.pending.len() >= MAX_PENDING_PACKETS {
+                return None
+            }
+        }
+        let complete = {
+            let entry = self.pending.entry(fragment.transaction_id.clone())
+                    .or_insert_with(|| (now, vec![None; fragment.count as usize]));
+            if entry.1.len() != fragment.count as usize {
+                entry.1 = vec![None; fragment.count as usize];
+            }
+            entry.1[fragment.index as usize] = Some(fragment.data);
+            entry.1.iter().all(Option::is_some)
+        };
+        if !complete {
+            return None
+        }
+        let (_, fragments) = self.pending.remove(&fragment.transaction_id).unwrap();
+        let encoded = fragments.into_iter().flat_map(Option::unwrap).collect::<Vec<u8>>();
+        Some(RoutePacket::decode(&encoded))
+    }
+
+    /// Drops the fragments of packets whose first fragment was received
+    /// `timeout` or more before `now`, and returns their transaction ids.
+    pub fn expire(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let timeout = self.timeout;
+        let expired = self.pending.iter()
+                .filter(|&(_, &(first_seen, _))| now.duration_since(first_seen) >= timeout)
+                .map(|(transaction_id, _)| transaction_id.clone())
+                .collect::<Vec<_>>();
+        for transaction_id in expired.iter() {
+            self.pending.remove(transaction_id);
+        }
+        expired
+    }
+
+    /// Returns the number of packets with fragments still missing.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
     }
 }
 
@@ -229,6 +858,10 @@ impl RoutePacketBuilder {
                 target_address: None,
                 transaction_id: transaction_id,
                 protocol_version: protocol_version,
+                reach: None,
+                received_time: None,
+                as_path: None,
+                more: None,
             }
         }
     }
@@ -237,6 +870,11 @@ impl RoutePacketBuilder {
         self.packet.query = Some(query);
         self
     }
+    /// Same as `query`, but takes a typed query.
+    pub fn route_query(mut self, query: RouteQuery) -> RoutePacketBuilder {
+        self.packet.query = Some(query.as_str().to_owned());
+        self
+    }
     pub fn encoding_index(mut self, encoding_index: i64) -> RoutePacketBuilder {
         self.packet.encoding_index = Some(encoding_index);
         self
@@ -255,14 +893,31 @@ impl RoutePacketBuilder {
     }
     /// Write `nodes` and `node_protocol_versions` in a single step,
     /// using `RoutePacket::write_nodes`.
-    pub fn nodes_vec(mut self, nodes: Vec<NodeData>) -> RoutePacketBuilder {
-        self.packet.write_nodes(nodes);
-        self
+    pub fn nodes_vec(mut self, nodes: Vec<NodeData>) -> Result<RoutePacketBuilder, String> {
+        try!(self.packet.write_nodes(nodes));
+        Ok(self)
     }
     pub fn target_address(mut self, target_address: Vec<u8>) -> RoutePacketBuilder {
         self.packet.target_address = Some(target_address);
         self
     }
+    pub fn reach(mut self, reach: i64) -> RoutePacketBuilder {
+        self.packet.reach = Some(reach);
+        self
+    }
+    pub fn received_time(mut self, received_time: i64) -> RoutePacketBuilder {
+        self.packet.received_time = Some(received_time);
+        self
+    }
+    pub fn more(mut self, more: i64) -> RoutePacketBuilder {
+        self.packet.more = Some(more);
+        self
+    }
+    /// Sets `as_path` to the concatenation of the given public keys.
+    pub fn as_path(mut self, public_keys: &[[u8; PUBLIC_KEY_LENGTH]]) -> RoutePacketBuilder {
+        self.packet.as_path = Some(public_keys.iter().flat_map(|key| key.iter().cloned()).collect());
+        self
+    }
 
     /// Finally produce the RoutePacket
     pub fn finalize(self) -> RoutePacket {
@@ -274,6 +929,7 @@ impl RoutePacketBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::iter::FromIterator;
 
     #[test]
     fn test_fn() {
@@ -304,6 +960,43 @@ mod tests {
         assert_eq!(m_encoded, s);
     }
 
+    #[test]
+    fn test_n_list() {
+        let compact = "d1:n80:cdefghijklmnopqrstuvwxyzabcdefghi1234567qponmlkjihgzyxwvutsrstuvwxyzabcde23456782:np3:\x01\x12\x111:pi18e4:txid5:12345e".as_bytes();
+        // Keys in canonical (sorted) order, so `np`, `p` and `txid`
+        // follow the list.
+        let list = "d1:nl40:cdefghijklmnopqrstuvwxyzabcdefghi123456740:qponmlkjihgzyxwvutsrstuvwxyzabcde2345678e2:np3:\x01\x12\x111:pi18e4:txid5:12345e".as_bytes();
+        let compact = RoutePacket::decode(compact).unwrap();
+        let list = RoutePacket::decode(list).unwrap();
+        assert_eq!(list, compact);
+        let nodes = list.read_nodes().unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(&nodes[0].public_key[..], &b"cdefghijklmnopqrstuvwxyzabcdefgh"[..]);
+        assert_eq!(nodes[0].path, *b"i1234567");
+        assert_eq!(nodes[0].version, 0x12);
+        assert_eq!(&nodes[1].public_key[..], &b"qponmlkjihgzyxwvutsrstuvwxyzabcd"[..]);
+        assert_eq!(nodes[1].version, 0x11);
+
+        // Items of the list have to be nodes
+        let s = "d1:nl3:fooe1:pi18e4:txid5:12345e".as_bytes();
+        match RoutePacket::decode(s) {
+            Err(RoutePacketError::BadType(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_bdecode() {
+        let mut expected = HashMap::new();
+        expected.insert(b"a".to_vec(), BValue::List(vec![BValue::List(vec![BValue::Integer(-1)]), BValue::String(b"x".to_vec())]));
+        expected.insert(b"b".to_vec(), BValue::Integer(2));
+        assert_eq!(bdecode(b"d1:alli-1ee1:xe1:bi2ee").unwrap(), BValue::Dictionary(expected));
+        assert!(bdecode(b"d1:ali1e").is_err());
+        assert!(bdecode(b"99999999999999999999999:a").is_err());
+        assert!(bdecode(b"5:abc").is_err());
+        assert!(bdecode(b"i99999999999999999999e").is_err());
+    }
+
     #[test]
     fn test_gp() {
         let s = b"d002:eii0e2:es5:a\x14E\x81\x001:pi17e1:q2:gp3:tar8:\x00\x00\x00\x00\x00\x00\x00\x004:txid12:\x0b\xf2\x17\xf4\x92\xa4\xc4d\xc6\x03[\xdde";
@@ -311,6 +1004,160 @@ mod tests {
         RoutePacket::decode(s).unwrap();
     }
 
+    #[test]
+    fn test_ping() {
+        let s = "d1:pi18e1:q2:pn4:txid5:12345e".as_bytes();
+        let ping = RoutePacketBuilder::new(18, b"12345".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        assert_eq!(ping.clone().encode(), s);
+        let ping = RoutePacket::decode(s).unwrap();
+        assert_eq!(ping.route_query(), Some(RouteQuery::Ping));
+
+        let s = "d1:pi18e4:txid5:12345e".as_bytes();
+        let pong = RoutePacketBuilder::new(18, ping.transaction_id.clone())
+                .finalize();
+        assert_eq!(pong.clone().encode(), s);
+        let pong = RoutePacket::decode(s).unwrap();
+        assert_eq!(pong.route_query(), None);
+        assert_eq!(pong.transaction_id, ping.transaction_id);
+    }
+
+    #[test]
+    fn test_reach_received_time() {
+        let s = "d1:pi18e5:reachi5e8:recvTimei1234e4:txid4:blahe".as_bytes();
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .reach(5)
+                .received_time(1234)
+                .finalize();
+        assert_eq!(packet.clone().encode(), s);
+        let decoded = RoutePacket::decode(s).unwrap();
+        assert_eq!(decoded.reach, Some(5));
+        assert_eq!(decoded.received_time, Some(1234));
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn test_reach_only() {
+        let s = "d1:pi18e5:reachi5e4:txid4:blahe".as_bytes();
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .reach(5)
+                .finalize();
+        assert_eq!(packet.clone().encode(), s);
+        let decoded = RoutePacket::decode(s).unwrap();
+        assert_eq!(decoded.reach, Some(5));
+        assert_eq!(decoded.received_time, None);
+        assert_eq!(decoded, packet);
+
+        let s = "d1:pi18e8:recvTimei1234e4:txid4:blahe".as_bytes();
+        let decoded = RoutePacket::decode(s).unwrap();
+        assert_eq!(decoded.reach, None);
+        assert_eq!(decoded.received_time, Some(1234));
+        assert_eq!(decoded.encode(), s);
+    }
+
+    #[test]
+    fn test_more() {
+        let s = "d4:morei1e1:pi18e4:txid4:blahe".as_bytes();
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .more(1)
+                .finalize();
+        assert_eq!(packet.clone().encode(), s);
+        let decoded = RoutePacket::decode(s).unwrap();
+        assert!(decoded.has_more());
+        assert_eq!(decoded, packet);
+
+        let decoded = RoutePacket::decode(b"d1:pi18e4:txid4:blahe").unwrap();
+        assert_eq!(decoded.more, None);
+        assert!(!decoded.has_more());
+    }
+
+    #[test]
+    fn test_as_path() {
+        let me = [1u8; 32];
+        let other = [2u8; 32];
+        let mut s = b"d6:asPath64:".to_vec();
+        s.extend_from_slice(&other);
+        s.extend_from_slice(&me);
+        s.extend_from_slice(b"1:pi18e4:txid4:blahe");
+
+        let decoded = RoutePacket::decode(&s).unwrap();
+        assert_eq!(decoded.as_path.as_ref().map(|p| p.len()), Some(64));
+        assert!(decoded.path_contains(&me));
+        assert!(decoded.path_contains(&other));
+        assert!(!decoded.path_contains(&[3u8; 32]));
+
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .as_path(&[other, me])
+                .finalize();
+        assert_eq!(decoded, packet);
+        assert_eq!(packet.encode(), s);
+
+        let decoded = RoutePacket::decode(b"d1:pi18e4:txid4:blahe").unwrap();
+        assert_eq!(decoded.as_path, None);
+        assert!(!decoded.path_contains(&me));
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let decoded = RoutePacket::decode(b"d1:pi18e4:txid4:blahe").unwrap();
+        assert_eq!(decoded.protocol_version(), Some(18));
+        assert_eq!(decoded, RoutePacketBuilder::new(18, b"blah".to_vec()).finalize());
+
+        let decoded = RoutePacket::decode(b"d1:pi0e4:txid4:blahe").unwrap();
+        assert_eq!(decoded.protocol_version(), Some(0));
+
+        let decoded = RoutePacket::decode(b"d1:pi-1e4:txid4:blahe").unwrap();
+        assert_eq!(decoded.protocol_version(), None);
+
+        // The version is mandatory.
+        assert!(RoutePacket::decode(b"d4:txid4:blahe").is_err());
+    }
+
+    #[test]
+    fn test_key_bytes() {
+        use fcp_cryptoauth;
+        use fcp_cryptoauth::wrapper::{PublicKey, gen_keypair};
+        fcp_cryptoauth::init();
+        let (pk, _) = gen_keypair();
+        let bytes = key_bytes(&pk);
+        assert_eq!(&bytes[..], &pk.0[..]);
+        assert_eq!(PublicKey::from_slice(&bytes), Some(pk));
+    }
+
+    #[test]
+    fn test_build_self_announcement() {
+        use std::iter::FromIterator;
+        use fcp_cryptoauth;
+        use fcp_cryptoauth::wrapper::gen_keypair;
+        use operation::SELF_LABEL;
+        fcp_cryptoauth::init();
+        let (pk, _) = gen_keypair();
+        let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+        let announcement = build_self_announcement(&pk, &SELF_LABEL, 18, &scheme).unwrap();
+        assert_eq!(announcement.query, None);
+        assert_eq!(announcement.protocol_version, 18);
+        assert_eq!(announcement.encoding_scheme, Some(scheme.clone()));
+        let nodes = announcement.read_nodes().unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].public_key, key_bytes(&pk));
+        assert_eq!(nodes[0].path, SELF_LABEL);
+        assert_eq!(nodes[0].version, 18);
+
+        let decoded = RoutePacket::decode(&announcement.clone().encode()).unwrap();
+        assert_eq!(decoded, announcement);
+
+        assert!(build_self_announcement(&pk, &SELF_LABEL, MAX_NODE_VERSION, &scheme).is_ok());
+        assert!(build_self_announcement(&pk, &SELF_LABEL, 256, &scheme).is_err());
+    }
+
+    #[test]
+    fn test_route_query() {
+        for query in vec![RouteQuery::FindNode, RouteQuery::GetPeers, RouteQuery::Ping, RouteQuery::Keepalive, RouteQuery::Other("foo".to_owned())] {
+            assert_eq!(RouteQuery::new(query.as_str()), query);
+        }
+    }
+
     #[test]
     fn test_read_write_nodes() {
         let mut packet = RoutePacket::decode(&vec![100,50,58,101,105,105,48,101,50,58,101,115,53,58,97,20,69,129,0,49,58,110,49,50,48,58,130,223,186,81,37,25,242,89,134,192,176,47,101,127,172,39,50,222,248,255,202,29,7,104,145,198,13,140,88,35,113,111,0,0,0,0,0,0,0,21,14,212,108,34,167,28,34,202,98,134,15,159,58,151,12,228,58,163,181,163,40,102,66,125,212,44,203,100,174,56,120,61,0,0,0,0,0,0,0,19,2,134,254,75,44,62,116,254,79,92,235,47,82,76,129,250,190,138,148,250,65,218,166,83,148,144,15,83,7,157,10,20,0,0,0,0,0,0,0,1,50,58,110,112,52,58,1,18,17,18,49,58,112,105,49,56,101,52,58,116,120,105,100,52,58,98,108,97,104,101]).unwrap();
@@ -335,13 +1182,368 @@ mod tests {
         assert_eq!(nodes[1], expected2);
         assert_eq!(nodes[2], expected3);
 
-        packet.write_nodes(vec![]);
+        packet.write_nodes(vec![]).unwrap();
         assert_eq!(packet.read_nodes().unwrap(), vec![]);
 
-        packet.write_nodes(vec![expected1.clone(), expected2.clone(), expected3.clone()]);
+        packet.write_nodes(vec![expected1.clone(), expected2.clone(), expected3.clone()]).unwrap();
         assert_eq!(nodes.len(), 3);
         assert_eq!(nodes[0], expected1);
         assert_eq!(nodes[1], expected2);
         assert_eq!(nodes[2], expected3);
     }
+
+    #[test]
+    fn test_is_routable() {
+        let scheme = EncodingScheme::from_iter([EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+        let node = NodeData {
+                public_key: [1u8; 32],
+                path: [0, 0, 0, 0, 0, 0, 0, 0b001_011],
+                version: 18,
+            };
+        assert!(node.is_routable(&scheme));
+        let corrupt = NodeData { path: [0, 0, 0, 0, 0, 0, 0, 0b010_011], ..node.clone() };
+        assert!(!corrupt.is_routable(&scheme));
+    }
+
+    #[test]
+    fn test_write_malformed_node() {
+        let node = NodeData {
+                public_key: [1u8; 32],
+                path: [0, 0, 0, 0, 0, 0, 0, 0x13],
+                version: 18,
+            };
+        let malformed = NodeData { version: 256, ..node.clone() };
+
+        let mut packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .nodes_vec(vec![node.clone()]).unwrap()
+                .finalize();
+        assert!(packet.write_nodes(vec![node.clone(), malformed.clone()]).is_err());
+        assert_eq!(packet.read_nodes().unwrap(), vec![node.clone()]);
+
+        assert!(encode_nodes(&[malformed.clone(), node.clone()]).is_err());
+
+        let mut nodes = Vec::new();
+        let mut versions = vec![1u8];
+        assert!(malformed.encode_into(&mut nodes, &mut versions).is_err());
+        assert!(nodes.is_empty());
+        assert_eq!(versions, vec![1u8]);
+    }
+
+    #[test]
+    fn test_encoding_form() {
+        let forms = vec![
+            EncodingSchemeForm { prefix: 1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+            EncodingSchemeForm { prefix: 0, bit_count: 8, prefix_length: 2 },
+        ];
+        let scheme = EncodingScheme::from_iter(forms.iter());
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .encoding_scheme(scheme.clone())
+                .encoding_index(1)
+                .finalize();
+        let packet = RoutePacket::decode(&packet.encode()).unwrap();
+        assert_eq!(packet.checked_encoding_index(), Some(1));
+        assert_eq!(packet.encoding_form(), Some(forms[1].clone()));
+
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .encoding_index(1)
+                .finalize();
+        assert_eq!(packet.checked_encoding_index(), None);
+        assert_eq!(packet.encoding_form(), None);
+
+        // Not decoded, so not validated yet.
+        let packet = RoutePacketBuilder::new(18, b"blah".to_vec())
+                .encoding_scheme(scheme)
+                .encoding_index(3)
+                .finalize();
+        assert_eq!(packet.checked_encoding_index(), None);
+        assert_eq!(packet.encoding_form(), None);
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let packets = vec![
+            b"d1:pi18e1:q2:fn3:tar16:abcdefghhijklmno4:txid5:12345e".to_vec(),
+            b"d1:pi18e1:q2:fn3:tar16:abcdefghhijklmno".to_vec(), // truncated
+            b"li18ee".to_vec(), // not a dict
+            b"d1:q2:gp4:txid5:12345e".to_vec(), // no protocol version
+            b"d1:pi17e4:txid4:blahe".to_vec(),
+        ];
+        let results = decode_all(&packets);
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap().query, Some("fn".to_owned()));
+        match results[1] {
+            Err(RoutePacketError::BencodeDecodeError(_)) => (),
+            ref r => panic!("Expected bencode error, got {:?}", r),
+        }
+        match results[2] {
+            Err(RoutePacketError::BadType(_)) => (),
+            ref r => panic!("Expected bad type error, got {:?}", r),
+        }
+        match results[3] {
+            Err(RoutePacketError::MissingKey(ref key)) => assert_eq!(key, "p"),
+            ref r => panic!("Expected missing key error, got {:?}", r),
+        }
+        assert_eq!(results[4].as_ref().unwrap().protocol_version, 17);
+    }
+
+    #[test]
+    fn test_encoding_index_out_of_range() {
+        // Same as in test_gp, but with the encoding index changed
+        let s = b"d002:eii0e2:es5:a\x14E\x81\x001:pi17e1:q2:gp3:tar8:\x00\x00\x00\x00\x00\x00\x00\x004:txid12:\x0b\xf2\x17\xf4\x92\xa4\xc4d\xc6\x03[\xdde";
+        let scheme_length = RoutePacket::decode(s).unwrap().encoding_scheme.unwrap().form_count();
+        assert_eq!(scheme_length, 3);
+
+        let s = b"d002:eii2e2:es5:a\x14E\x81\x001:pi17e1:q2:gp3:tar8:\x00\x00\x00\x00\x00\x00\x00\x004:txid12:\x0b\xf2\x17\xf4\x92\xa4\xc4d\xc6\x03[\xdde";
+        assert_eq!(RoutePacket::decode(s).unwrap().encoding_index, Some(2));
+
+        let s = b"d002:eii3e2:es5:a\x14E\x81\x001:pi17e1:q2:gp3:tar8:\x00\x00\x00\x00\x00\x00\x00\x004:txid12:\x0b\xf2\x17\xf4\x92\xa4\xc4d\xc6\x03[\xdde";
+        match RoutePacket::decode(s) {
+            Err(RoutePacketError::EncodingIndexOutOfRange(3)) => (),
+            r => panic!("Expected out of range error, got {:?}", r),
+        }
+
+        let s = b"d002:eii-1e2:es5:a\x14E\x81\x001:pi17e1:q2:gp3:tar8:\x00\x00\x00\x00\x00\x00\x00\x004:txid12:\x0b\xf2\x17\xf4\x92\xa4\xc4d\xc6\x03[\xdde";
+        match RoutePacket::decode(s) {
+            Err(RoutePacketError::EncodingIndexOutOfRange(-1)) => (),
+            r => panic!("Expected out of range error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_find_node() {
+        let target = (0..16).collect::<Vec<u8>>();
+        let query = RoutePacket::find_node(18, b"fn txid".to_vec(), target.clone());
+        let decoded = RoutePacket::decode(&query.clone().encode()).unwrap();
+        assert_eq!(decoded, query);
+        assert!(decoded.is_find_node());
+        assert_eq!(decoded.route_query(), Some(RouteQuery::FindNode));
+        assert_eq!(decoded.find_node_target(), Some(&target[..]));
+
+        let raw = b"d1:pi18e1:q2:fn3:tar16:abcdefghhijklmno4:txid5:12345e";
+        let decoded = RoutePacket::decode(raw).unwrap();
+        assert!(decoded.is_find_node());
+        assert_eq!(decoded.find_node_target(), Some(&b"abcdefghhijklmno"[..]));
+
+        // Bad or missing targets
+        let query = RoutePacket::find_node(18, b"fn txid".to_vec(), vec![0; 15]);
+        assert!(query.is_find_node());
+        assert_eq!(query.find_node_target(), None);
+        let query = RoutePacketBuilder::new(18, b"fn txid".to_vec())
+                .route_query(RouteQuery::FindNode)
+                .finalize();
+        assert_eq!(query.find_node_target(), None);
+
+        // Not fn queries
+        let gp = RoutePacket::get_peers(18, b"gp txid".to_vec(), target);
+        assert!(!gp.is_find_node());
+        assert_eq!(gp.find_node_target(), None);
+    }
+
+    #[test]
+    fn test_view() {
+        let scheme = EncodingScheme::from_iter(vec![
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        ].iter());
+        let nodes = vec![
+            NodeData { public_key: [1; 32], path: [0, 0, 0, 0, 0, 0, 0, 0b0001_011_1], version: 18 },
+            NodeData { public_key: [2; 32], path: [0, 0, 0, 0, 0, 0, 0, 0b0001], version: 17 },
+        ];
+        let packet = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .nodes_vec(nodes).unwrap()
+                .encoding_scheme(scheme.clone())
+                .encoding_index(1)
+                .target_address(vec![3; 16])
+                .reach(42)
+                .more(1)
+                .finalize();
+        let packet = RoutePacket::decode(&packet.encode()).unwrap();
+
+        let view = packet.view().unwrap();
+        assert_eq!(view.query, packet.route_query());
+        assert_eq!(view.transaction_id, packet.transaction_id);
+        assert_eq!(view.nodes, Some(packet.read_nodes().unwrap()));
+        assert_eq!(view.encoding_scheme, packet.encoding_scheme);
+        assert_eq!(view.encoding_index, packet.checked_encoding_index());
+        assert_eq!(view.encoding_index, Some(1));
+        assert_eq!(view.encoding_form, packet.encoding_form());
+        assert_eq!(view.target_address, packet.target_address);
+        assert_eq!(view.protocol_version, packet.protocol_version);
+        assert_eq!(view.reach, Some(42));
+        assert_eq!(view.more, packet.has_more());
+
+        let empty = RoutePacketBuilder::new(18, b"txid".to_vec()).finalize().view().unwrap();
+        assert_eq!(empty.query, None);
+        assert_eq!(empty.nodes, None);
+        assert_eq!(empty.encoding_form, None);
+        assert!(!empty.more);
+
+        let malformed = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .nodes(vec![0; 39])
+                .finalize();
+        assert!(malformed.view().is_err());
+    }
+
+    #[test]
+    fn test_typed_constructors() {
+        let gp = RoutePacket::get_peers(18, b"txid".to_vec(), vec![0; 16]);
+        assert_eq!(gp.route_query(), Some(RouteQuery::GetPeers));
+        assert_eq!(gp, RoutePacketBuilder::new(18, b"txid".to_vec())
+                .query("gp".to_owned())
+                .target_address(vec![0; 16])
+                .finalize());
+        assert_eq!(RoutePacket::decode(&gp.clone().encode()).unwrap(), gp);
+
+        let fn_ = RoutePacket::find_node(18, b"12345".to_vec(), b"abcdefghhijklmno".to_vec());
+        assert_eq!(fn_.clone().encode(), b"d1:pi18e1:q2:fn3:tar16:abcdefghhijklmno4:txid5:12345e".to_vec());
+
+        let pn = RoutePacket::ping(17, b"txid".to_vec());
+        assert_eq!(pn.route_query(), Some(RouteQuery::Ping));
+        assert_eq!(pn.protocol_version, 17);
+        assert_eq!(pn.target_address, None);
+
+        let ka = RoutePacket::keepalive(18, b"txid".to_vec());
+        assert_eq!(ka.route_query(), Some(RouteQuery::Keepalive));
+        assert_eq!(RoutePacket::decode(&ka.clone().encode()).unwrap(), ka);
+    }
+
+    #[test]
+    fn test_nodes_match_scheme() {
+        use operation::label_from_u64;
+        let node = NodeData {
+            public_key: [1; 32],
+            path: [0, 0, 0, 0, 0, 0, 0, 0b001_011],
+            version: 18,
+        };
+        let three_bits = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+        // No form for directors ending with 0b00
+        let variable = EncodingScheme::from_iter(vec![
+            EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        ].iter());
+
+        let packet = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .nodes_vec(vec![node.clone()]).unwrap()
+                .finalize();
+        assert!(packet.nodes_match_scheme()); // No scheme
+
+        let packet = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .nodes_vec(vec![node.clone()]).unwrap()
+                .encoding_scheme(three_bits.clone())
+                .finalize();
+        assert!(packet.nodes_match_scheme());
+
+        // The first director is in the emitter's scheme, the next one
+        // is in the 3-bit scheme of the next switch.
+        let multi_hop = NodeData {
+            public_key: [2; 32],
+            path: label_from_u64(0b001_011_101_1),
+            version: 18,
+        };
+        assert!(!multi_hop.is_routable(&variable));
+        let packet = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .nodes_vec(vec![multi_hop]).unwrap()
+                .encoding_scheme(variable.clone())
+                .finalize();
+        assert!(packet.nodes_match_scheme());
+
+        let bad_first_director = NodeData {
+            public_key: [3; 32],
+            path: [0, 0, 0, 0, 0, 0, 0, 0b0001_00],
+            version: 18,
+        };
+        let packet = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .nodes_vec(vec![node.clone(), bad_first_director]).unwrap()
+                .encoding_scheme(variable.clone())
+                .finalize();
+        assert!(!packet.nodes_match_scheme());
+
+        let packet = RoutePacketBuilder::new(18, b"txid".to_vec())
+                .encoding_scheme(variable)
+                .finalize();
+        assert!(packet.nodes_match_scheme()); // No nodes
+    }
+
+    #[test]
+    fn test_reassembler() {
+        use std::time::{Duration, Instant};
+        let nodes = (0..10u8).map(|i| NodeData {
+            public_key: [i; 32],
+            path: [0, 0, 0, 0, 0, 0, 0, i],
+            version: 18,
+        }).collect();
+        let packet = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .nodes_vec(nodes).unwrap()
+                .finalize();
+        let encoded_length = packet.clone().encode().len();
+        let fragments = RouteFragment::split(packet.clone(), encoded_length / 2 + 1);
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[1].index, 1);
+        assert_eq!(fragments[1].count, 2);
+
+        // Fragments may arrive in any order.
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        assert!(reassembler.add_fragment(fragments[1].clone(), start).is_none());
+        assert_eq!(reassembler.pending_count(), 1);
+        assert_eq!(reassembler.add_fragment(fragments[0].clone(), start).unwrap().unwrap(), packet);
+        assert_eq!(reassembler.pending_count(), 0);
+
+        // Out of range fragments are ignored.
+        let mut bad = fragments[0].clone();
+        bad.index = 2;
+        assert!(reassembler.add_fragment(bad, start).is_none());
+        assert_eq!(reassembler.pending_count(), 0);
+
+        // A missing fragment: the packet is dropped after the timeout.
+        assert!(reassembler.add_fragment(fragments[0].clone(), start).is_none());
+        assert_eq!(reassembler.expire(start + Duration::from_secs(5)), Vec::<Vec<u8>>::new());
+        assert_eq!(reassembler.expire(start + Duration::from_secs(10)), vec![b"gp txid".to_vec()]);
+        assert_eq!(reassembler.pending_count(), 0);
+        // A late fragment does not complete it.
+        assert!(reassembler.add_fragment(fragments[1].clone(), start + Duration::from_secs(11)).is_none());
+    }
+
+    #[test]
+    fn test_reassembler_limits() {
+        use std::time::{Duration, Instant};
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        let fragment = |transaction_id: usize, count: u16| RouteFragment {
+            transaction_id: transaction_id.to_string().into_bytes(),
+            index: 0,
+            count: count,
+            data: vec![0; 100],
+        };
+
+        // Too many fragments
+        assert!(reassembler.add_fragment(fragment(0, MAX_FRAGMENT_COUNT + 1), start).is_none());
+        assert_eq!(reassembler.pending_count(), 0);
+        assert!(reassembler.add_fragment(fragment(0, MAX_FRAGMENT_COUNT), start).is_none());
+        assert_eq!(reassembler.pending_count(), 1);
+
+        // Too many packets
+        for transaction_id in 1..(MAX_PENDING_PACKETS + 1) {
+            assert!(reassembler.add_fragment(fragment(transaction_id, 2), start).is_none());
+        }
+        assert_eq!(reassembler.pending_count(), MAX_PENDING_PACKETS);
+        assert!(!reassembler.pending.contains_key(&MAX_PENDING_PACKETS.to_string().into_bytes()));
+        // Fragments of pending packets are still accepted.
+        let mut last = fragment(1, 2);
+        last.index = 1;
+        assert!(reassembler.add_fragment(last, start).unwrap().is_err());
+        assert_eq!(reassembler.pending_count(), MAX_PENDING_PACKETS - 1);
+
+        // Once the pending packets expired, new ones are accepted.
+        assert!(reassembler.add_fragment(fragment(1, 2), start).is_none());
+        assert!(reassembler.add_fragment(fragment(1000, 2), start + Duration::from_secs(10)).is_none());
+        assert_eq!(reassembler.pending_count(), 1);
+
+        // Changing the count does not delay the expiration.
+        assert!(reassembler.add_fragment(fragment(1000, 3), start + Duration::from_secs(15)).is_none());
+        assert_eq!(reassembler.pending_count(), 1);
+        assert_eq!(reassembler.expire(start + Duration::from_secs(20)), vec![b"1000".to_vec()]);
+    }
 }
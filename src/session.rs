@@ -0,0 +1,43 @@
+//! Contains the `Session` trait, which abstracts the point-to-point
+//! (aka outer) CryptoAuth sessions used by the switch to talk to its peers.
+
+use fcp_cryptoauth::wrapper::{Wrapper, PublicKey, AuthFailure};
+
+/// A session with a direct peer of the switch. Implemented by
+/// CryptoAuth's `Wrapper`; other implementations can be used for
+/// alternative transports, or to test the switch without handshakes.
+pub trait Session {
+    /// Takes an unencrypted message and returns the packets to send
+    /// to the peer.
+    /// Returns no packet if the message cannot be sent yet (eg. during
+    /// a handshake); the switch then wraps it again on its next upkeep.
+    fn wrap_message(&mut self, msg: &[u8]) -> Vec<Vec<u8>>;
+    /// Takes a packet received from the peer and returns the messages
+    /// it contains.
+    fn unwrap_message(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>, AuthFailure>;
+    /// Performs internal maintainance, and returns the packets to send
+    /// to the peer.
+    fn upkeep(&mut self) -> Vec<Vec<u8>>;
+    /// Returns the session handle the peer asked us to use, if any.
+    fn peer_session_handle(&self) -> Option<u32>;
+    /// Returns the public key of the peer.
+    fn their_pk(&self) -> &PublicKey;
+}
+
+impl<PeerId: Clone> Session for Wrapper<PeerId> {
+    fn wrap_message(&mut self, msg: &[u8]) -> Vec<Vec<u8>> {
+        Wrapper::wrap_message(self, msg)
+    }
+    fn unwrap_message(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>, AuthFailure> {
+        Wrapper::unwrap_message(self, packet)
+    }
+    fn upkeep(&mut self) -> Vec<Vec<u8>> {
+        Wrapper::upkeep(self)
+    }
+    fn peer_session_handle(&self) -> Option<u32> {
+        Wrapper::peer_session_handle(self)
+    }
+    fn their_pk(&self) -> &PublicKey {
+        Wrapper::their_pk(self)
+    }
+}
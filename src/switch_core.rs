@@ -0,0 +1,2370 @@
+//! Contains the `SwitchCore` structure, which implements the logic of a
+//! switch: routing switch packets between its peers, answering
+//! control packets, and managing end-to-end CryptoAuth sessions with
+//! routers.
+//!
+//! `SwitchCore` does not perform any I/O: it is given the datagrams
+//! received from peers, and returns the datagrams that should be sent,
+//! along with the address of the peer they should be sent to.
+
+use std::net::SocketAddr;
+use std::iter::FromIterator;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::error;
+
+use rand;
+use rand::Rng;
+
+use fcp_cryptoauth::wrapper::*;
+use fcp_cryptoauth::handshake_packet::HandshakePacket;
+use fcp_cryptoauth::cryptography::scalarmult;
+
+use session::Session;
+
+use switch_packet::SwitchPacket;
+use switch_packet::Payload as SwitchPayload;
+use switch_packet::{parse_outer, OuterEvent, MIN_SWITCH_PACKET_LENGTH};
+use operation::{RoutingDecision, Director, Label, SELF_LABEL, reverse_director, is_self_route};
+use operation::{convert_label, splice, ConvertError, label_from_u64, u64_from_label};
+use control::{ControlPacket, ControlPacketBuilder};
+use PROTOCOL_VERSION;
+use route_packet::{RoutePacket, RoutePacketBuilder, RouteQuery, NodeData, key_bytes, MAX_NODE_VERSION};
+use data_packet::DataPacket;
+use data_packet::Payload as DataPayload;
+use encoding_scheme::{EncodingScheme, EncodingSchemeForm};
+
+/// The only form of the encoding scheme used by this switch.
+const ENCODING_FORM: EncodingSchemeForm = EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 };
+
+/// Id of the self interface, which packets originated by this switch
+/// (eg. replies) are sent from. Its director is the self route,
+/// `SELF_LABEL`, in `ENCODING_FORM`.
+const SELF_INTERFACE: u8 = 0b001;
+
+/// Used to represent a connection to a *direct peer* of this switch.
+pub struct Interface {
+    /// Used for routing -- it is the Director.
+    pub id: u8,
+    /// A point-to-point (aka outer) CryptoAuth session.
+    pub ca_session: Box<dyn Session + Send>,
+    /// The address where to send the UDP packets to.
+    pub addr: SocketAddr,
+}
+
+/// Immutable snapshot of the interfaces of a switch, mapping their id
+/// (ie. their director) to the address of their peer. Returned by
+/// `SwitchCore::interface_map`.
+///
+/// It does not borrow the switch, and is `Send` and `Sync`, so it can
+/// be shared (eg. in an `Arc`) by threads routing packets between
+/// peers, while the switch keeps handling the other packets. Later
+/// changes to the interfaces of the switch are not reflected: take an
+/// other snapshot.
+///
+/// It does not hold the outer CryptoAuth sessions of the interfaces, so
+/// the packets it routes still have to be encrypted by the switch, with
+/// `SwitchCore::wrap_for_interfaces`, before being sent.
+#[derive(Clone, Debug)]
+pub struct InterfaceMap {
+    addrs: HashMap<u8, SocketAddr>,
+}
+
+impl InterfaceMap {
+    /// Returns the address of the peer of an interface.
+    pub fn addr(&self, iface_id: u8) -> Option<SocketAddr> {
+        self.addrs.get(&iface_id).cloned()
+    }
+
+    /// Switches a packet received on interface `from_interface`, if it
+    /// is to be forwarded to the peer of an other interface of the map,
+    /// and returns that interface and the switched packet, which is not
+    /// encrypted yet (see `SwitchCore::wrap_for_interfaces`).
+    ///
+    /// Any other packet (for the self interface, with a loop route, for
+    /// an unknown interface, ...) is returned unchanged as an error, to
+    /// be handled by `SwitchCore`.
+    pub fn forward(&self, mut packet: SwitchPacket, from_interface: u8) -> Result<(u8, SwitchPacket), SwitchPacket> {
+        let iface_id = packet.next_director(ENCODING_FORM.bit_count) as u8;
+        if !self.addrs.contains_key(&iface_id) || iface_id == from_interface || packet.is_next_hop_self(ENCODING_FORM.bit_count) {
+            return Err(packet)
+        }
+        let reversed_origin_iface = reverse_director(from_interface as Director, ENCODING_FORM.bit_count);
+        match packet.switch(ENCODING_FORM.bit_count, &reversed_origin_iface) {
+            Ok(decision) => {
+                debug_assert_eq!(decision.iface_id(), Some(iface_id));
+                Ok((iface_id, packet))
+            },
+            Err(_) => Err(packet), // Unchanged
+        }
+    }
+}
+
+/// Creates a reply switch packet to an other switch packet.
+/// The content of the reply is given as a byte array (returned CryptoAuth's
+/// `wrap_messages`).
+/// Returns None if CryptoAuth returned a data message before the peer
+/// told us its session handle.
+fn make_reply(replied_to_packet: &SwitchPacket, reply_content: Vec<u8>, inner_conn: &Wrapper<()>) -> Option<SwitchPacket> {
+    // CryptoAuth handshake packets are sent as they are, data packets
+    // are prefixed with the session handle, which is used by the peer
+    // to know this packet is coming from us. Control packets are not
+    // sent through CryptoAuth sessions, so the content is never one.
+    SwitchPacket::from_ca_message(&replied_to_packet.reply_label(), inner_conn.peer_session_handle(), reply_content)
+}
+
+/// Settings of a `SwitchCore`.
+#[derive(Clone, Debug)]
+pub struct SwitchConfig {
+    /// Whether the switch sometimes sends pings and `gp` queries on its
+    /// own, to discover and keep track of the network. If false, the
+    /// switch only replies to the packets it receives.
+    pub proactive_discovery: bool,
+    /// The protocol version advertised in every control and route packet
+    /// emitted by the switch, and in its own entry of `gp` responses
+    /// (where it is announced as `route_packet::MAX_NODE_VERSION` if
+    /// it is greater).
+    pub version: u32,
+    /// Routers announcing, in their route packets, a protocol version
+    /// lower than this one are rejected: their inner CryptoAuth session
+    /// is closed and the packet is dropped.
+    pub min_peer_version: u32,
+    /// If set, responses to `gp` (and `fn`) queries are kept this long,
+    /// and reused (with the transaction id of the new query) for queries
+    /// of the same type from the same session, instead of being built
+    /// again. The target address is not taken into account, as it does
+    /// not change the response.
+    pub response_cache_ttl: Option<Duration>,
+    /// How long packets which an outer session could not wrap yet (eg.
+    /// during its handshake) are kept and retried before being dropped.
+    pub pending_packet_ttl: Duration,
+    /// If set, end-to-end (aka inner) CryptoAuth sessions on which no
+    /// message was received for this long are closed on `upkeep`, as
+    /// with `SwitchCore::remove_session`. Routers can send `ka` queries
+    /// to keep idle sessions open.
+    pub session_ttl: Option<Duration>,
+}
+
+impl Default for SwitchConfig {
+    fn default() -> SwitchConfig {
+        SwitchConfig {
+            proactive_discovery: true,
+            version: PROTOCOL_VERSION,
+            min_peer_version: 0,
+            response_cache_ttl: None,
+            pending_packet_ttl: Duration::from_secs(10),
+            session_ttl: None,
+        }
+    }
+}
+
+/// Chooses the handles of the end-to-end (aka inner) CryptoAuth
+/// sessions opened by routers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandleAllocator {
+    /// Random handles.
+    Random,
+    /// Consecutive handles, starting from this one. Makes handles
+    /// predictable, eg. for tests.
+    Sequential(u32),
+}
+
+impl HandleAllocator {
+    /// Returns a new handle. It may already be in use.
+    pub fn next_handle(&mut self) -> u32 {
+        match *self {
+            HandleAllocator::Random => rand::thread_rng().next_u32(),
+            HandleAllocator::Sequential(ref mut next) => {
+                let handle = *next;
+                *next = next.wrapping_add(1);
+                handle
+            }
+        }
+    }
+}
+
+impl Default for HandleAllocator {
+    fn default() -> HandleAllocator {
+        HandleAllocator::Random
+    }
+}
+
+/// Reasons why `SwitchCore::from_keys` cannot build a switch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// The secret key is not 64 hexadecimal digits.
+    InvalidSecretKey,
+    /// The public key is not a base32-encoded key ending with `.k`.
+    InvalidPublicKey,
+    /// The public key is not the one of the secret key.
+    KeyMismatch,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyError::InvalidSecretKey => write!(f, "invalid secret key"),
+            KeyError::InvalidPublicKey => write!(f, "invalid public key"),
+            KeyError::KeyMismatch => write!(f, "public key does not match the secret key"),
+        }
+    }
+}
+
+impl error::Error for KeyError {}
+
+/// Handles below this one cannot be used, because data packets starting
+/// with them are read as CryptoAuth handshake packets.
+const MIN_SESSION_HANDLE: u32 = 4;
+
+/// This handle cannot be used either, because data packets starting with
+/// it are read as control packets.
+const CONTROL_HANDLE: u32 = 0xffffffff;
+
+/// Maximum number of packets kept until their outer session can wrap
+/// them. Further packets are dropped.
+const MAX_PENDING_PACKETS: usize = 256;
+
+/// Length of the header of a CryptoAuth handshake packet, which
+/// contains the sender's public key.
+const HANDSHAKE_HEADER_LENGTH: usize = 120;
+
+/// What the caller of `SwitchCore::handle_messages` should do with
+/// the outcome of a batch of messages.
+#[derive(Debug)]
+pub enum Action {
+    /// The packet should be sent through the interface with this id,
+    /// once wrapped with `SwitchCore::wrap_for_interfaces`.
+    Forward(u8, SwitchPacket),
+    /// The message at this position of the batch was dropped, because
+    /// it is not a valid switch packet.
+    Drop(usize),
+}
+
+/// Maximum size of the datagrams sent to peers: the largest UDP payload
+/// fitting in an Ethernet frame over IPv6 (1500 - 40 - 8).
+pub const SWITCH_MTU: usize = 1452;
+
+/// Size added by CryptoAuth to data packets: a nonce and an
+/// authenticator.
+const CRYPTOAUTH_DATA_OVERHEAD: usize = 4 + 16;
+
+/// Size of the header of data packets.
+const DATA_HEADER_LENGTH: usize = 4;
+
+/// Size of a node in a `gp` response: its public key and path in the node
+/// list (`n`), and its version in the node version list (`np`).
+const NODE_ENCODED_LENGTH: usize = 32 + 8 + 1;
+
+/// Size of `more` (`4:morei1e`) in an encoded route packet.
+const MORE_ENCODED_LENGTH: usize = 9;
+
+/// Query and querying session of a cached response.
+type ResponseCacheKey = (String, u32);
+
+/// Maximum number of responses kept by the response cache. See
+/// `SwitchConfig::response_cache_ttl`.
+const MAX_CACHED_RESPONSES: usize = 64;
+
+/// Maximum number of paths remembered as dead. The oldest ones are
+/// forgotten first.
+const MAX_DEAD_ROUTES: usize = 1024;
+
+/// Main data structure of the switch.
+pub struct SwitchCore {
+    /// Peers
+    interfaces: Vec<Interface>,
+    /// My public key, both for outer and inner CryptoAuth sessions.
+    my_pk: PublicKey,
+    /// My public key, both for outer and inner CryptoAuth sessions.
+    my_sk: SecretKey,
+    /// CryptoAuth sessions used to talk to switches/routers. Their packets
+    /// themselves are wrapped in SwitchPackets, which are wrapped in the
+    /// outer CryptoAuth sessions.
+    inner_conns: HashMap<u32, ([u8; 8], Wrapper<()>)>,
+    /// Protocol versions announced by routers in the route packets they
+    /// sent on inner CryptoAuth sessions, indexed by session handle.
+    peer_versions: HashMap<u32, u64>,
+    /// Encoding schemes announced by routers in the route packets they
+    /// sent on inner CryptoAuth sessions, indexed by session handle.
+    peer_schemes: HashMap<u32, EncodingScheme>,
+    /// Handle of the inner CryptoAuth session opened by each router,
+    /// indexed by the router's public key, to recognize re-sent hellos.
+    inner_handshakes: HashMap<[u8; 32], u32>,
+    /// When the last message was received on each inner CryptoAuth
+    /// session, indexed by session handle.
+    last_seen: HashMap<u32, Instant>,
+    /// Nodes imported with `import_nodes`, which we may not have
+    /// a session with.
+    known_nodes: HashSet<NodeData>,
+    /// Paths which a switch reported it could not forward packets on.
+    dead_routes: HashSet<Label>,
+    /// Same paths as `dead_routes`, oldest first, to forget the oldest
+    /// ones when there are more than `MAX_DEAD_ROUTES`.
+    dead_routes_order: VecDeque<Label>,
+    /// Credentials of peers which are allowed to connect to us.
+    allowed_peers: HashMap<Credentials, String>,
+    /// Number of packets which were dropped because they could not be
+    /// handled.
+    dropped_packets: u64,
+    /// Settings of this switch.
+    config: SwitchConfig,
+    /// Called with route packets whose query the switch does not handle.
+    unknown_query_hook: Option<Box<dyn FnMut(&str, &RoutePacket) + Send>>,
+    /// Packets which an outer session could not wrap yet (eg. during its
+    /// handshake), along with when they were first sent and the id of
+    /// their interface. Retried on `upkeep`, until
+    /// `SwitchConfig::pending_packet_ttl`. At most `MAX_PENDING_PACKETS`.
+    pending_packets: Vec<(Instant, u8, SwitchPacket)>,
+    /// Chooses the handles of new inner CryptoAuth sessions.
+    handle_allocator: HandleAllocator,
+    /// Responses to `gp` queries, indexed by query and querying session
+    /// (which is left out of the response). See
+    /// `SwitchConfig::response_cache_ttl`.
+    response_cache: HashMap<ResponseCacheKey, RoutePacket>,
+    /// Keys of `response_cache`, along with when their response was
+    /// built, oldest first; so expired responses are found without
+    /// scanning the cache.
+    response_cache_order: VecDeque<(Instant, ResponseCacheKey)>,
+}
+
+impl SwitchCore {
+    /// Instanciates a switch, with the default configuration.
+    pub fn new(interfaces: Vec<Interface>, my_pk: PublicKey, my_sk: SecretKey, allowed_peers: HashMap<Credentials, String>) -> SwitchCore {
+        SwitchCore::with_config(interfaces, my_pk, my_sk, allowed_peers, SwitchConfig::default())
+    }
+
+    /// Instanciates a switch.
+    pub fn with_config(interfaces: Vec<Interface>, my_pk: PublicKey, my_sk: SecretKey, allowed_peers: HashMap<Credentials, String>, config: SwitchConfig) -> SwitchCore {
+        SwitchCore {
+            interfaces: interfaces,
+            inner_conns: HashMap::new(),
+            peer_versions: HashMap::new(),
+            peer_schemes: HashMap::new(),
+            inner_handshakes: HashMap::new(),
+            last_seen: HashMap::new(),
+            known_nodes: HashSet::new(),
+            dead_routes: HashSet::new(),
+            dead_routes_order: VecDeque::new(),
+            my_pk: my_pk,
+            my_sk: my_sk,
+            allowed_peers: allowed_peers,
+            dropped_packets: 0,
+            config: config,
+            unknown_query_hook: None,
+            pending_packets: Vec::new(),
+            handle_allocator: HandleAllocator::default(),
+            response_cache: HashMap::new(),
+            response_cache_order: VecDeque::new(),
+            }
+    }
+
+    /// Instanciates a switch with the default configuration, from a
+    /// hex-encoded secret key and a base32-encoded public key (as found
+    /// in cjdns' configuration files), checking they are a key pair.
+    pub fn from_keys(sk_hex: &str, pk_base32: &str, interfaces: Vec<Interface>, allowed_peers: HashMap<Credentials, String>) -> Result<SwitchCore, KeyError> {
+        let my_sk = match SecretKey::from_hex(sk_hex.as_bytes()) {
+            Some(sk) => sk,
+            None => return Err(KeyError::InvalidSecretKey),
+        };
+        if !pk_base32.ends_with(".k") {
+            // from_base32 panics on these.
+            return Err(KeyError::InvalidPublicKey)
+        }
+        let my_pk = match PublicKey::from_base32(pk_base32.as_bytes()) {
+            Some(pk) => pk,
+            None => return Err(KeyError::InvalidPublicKey),
+        };
+        let derived_pk = scalarmult::scalarmult_base(&scalarmult::Scalar(my_sk.0));
+        if derived_pk.0 != my_pk.0 {
+            return Err(KeyError::KeyMismatch)
+        }
+        Ok(SwitchCore::new(interfaces, my_pk, my_sk, allowed_peers))
+    }
+
+    /// Sets a function called with each route packet received from
+    /// a router with a query the switch does not handle (anything but
+    /// `gp`, `fn`, `pn` and `ka`), along with that query; eg. to log them or to
+    /// reply to them. Replaces the previous one, if any.
+    ///
+    /// Without it, such packets are ignored.
+    pub fn set_unknown_query_hook<F: FnMut(&str, &RoutePacket) + Send + 'static>(&mut self, hook: F) {
+        self.unknown_query_hook = Some(Box::new(hook));
+    }
+
+    /// Sets how the handles of new inner CryptoAuth sessions are chosen.
+    /// Handles already in use, lower than 4, or equal to `0xffffffff`
+    /// (the control packet marker) are skipped.
+    pub fn set_handle_allocator(&mut self, allocator: HandleAllocator) {
+        self.handle_allocator = allocator;
+    }
+
+    /// Returns the maximum size of the content of a data packet (eg.
+    /// an encoded route packet) sent to a router, so that it fits in
+    /// a single datagram of `SWITCH_MTU` bytes once wrapped in the inner
+    /// CryptoAuth session, the switch packet, and the outer CryptoAuth
+    /// session.
+    pub fn payload_mtu(&self) -> usize {
+        SWITCH_MTU
+            - CRYPTOAUTH_DATA_OVERHEAD // outer session
+            - MIN_SWITCH_PACKET_LENGTH // switch header and session handle
+            - CRYPTOAUTH_DATA_OVERHEAD // inner session
+            - DATA_HEADER_LENGTH
+    }
+
+    /// Returns a snapshot of the interfaces of the switch, which can be
+    /// used to forward packets between peers without borrowing it.
+    pub fn interface_map(&self) -> InterfaceMap {
+        InterfaceMap {
+            addrs: self.interfaces.iter().map(|iface| (iface.id, iface.addr)).collect(),
+        }
+    }
+
+    /// Returns the number of packets which were dropped, eg. because they
+    /// referenced an unknown session, or had a loop route.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    /// Returns the nodes known by this switch: routers with an inner
+    /// CryptoAuth session, and nodes imported with `import_nodes`.
+    /// Their path is relative to this switch.
+    ///
+    /// Can be saved, to restore them with `import_nodes` after a restart.
+    pub fn export_nodes(&self) -> Vec<NodeData> {
+        let mut nodes = self.known_nodes.clone();
+        for (handle, &(path, ref inner_conn)) in self.inner_conns.iter() {
+            // Replace imported nodes by the ones we have a session with.
+            nodes.replace(NodeData {
+                public_key: key_bytes(inner_conn.their_pk()),
+                path: path,
+                version: self.peer_version(*handle),
+            });
+        }
+        let mut nodes = nodes.into_iter().collect::<Vec<_>>();
+        nodes.sort();
+        nodes
+    }
+
+    /// Returns the protocol version of the router on the inner session
+    /// `handle`, as announced in the last route packet it sent us, or
+    /// our own version if it did not send any yet.
+    fn peer_version(&self, handle: u32) -> u64 {
+        self.peer_versions.get(&handle).cloned().unwrap_or(::std::cmp::min(self.config.version as u64, MAX_NODE_VERSION))
+    }
+
+    /// Returns a builder for the control packets emitted by the switch,
+    /// using the configured version.
+    fn control_packet_builder(&self) -> ControlPacketBuilder {
+        ControlPacketBuilder::new().version(self.config.version)
+    }
+
+    /// Returns a builder for the route packets emitted by the switch,
+    /// using the configured version.
+    fn route_packet_builder(&self, transaction_id: Vec<u8>) -> RoutePacketBuilder {
+        RoutePacketBuilder::new(self.config.version as i64, transaction_id)
+    }
+
+    /// Returns the end-to-end (aka inner) CryptoAuth sessions of the
+    /// switch, with their handle, the path to the other end, and its
+    /// public key. In no particular order.
+    pub fn sessions<'a>(&'a self) -> impl Iterator<Item=(u32, &'a Label, &'a PublicKey)> + 'a {
+        self.inner_conns.iter().map(|(handle, &(ref path, ref inner_conn))| (*handle, path, inner_conn.their_pk()))
+    }
+
+    /// Closes the end-to-end (aka inner) CryptoAuth session `handle`,
+    /// eg. because the router behind it stopped answering. It is not
+    /// announced in `gp` responses anymore.
+    ///
+    /// Returns whether there was such a session.
+    pub fn remove_session(&mut self, handle: u32) -> bool {
+        self.peer_versions.remove(&handle);
+        self.peer_schemes.remove(&handle);
+        self.inner_handshakes.retain(|_, &mut session_handle| session_handle != handle);
+        self.last_seen.remove(&handle);
+        // Cached responses may announce this session.
+        self.response_cache.clear();
+        self.response_cache_order.clear();
+        self.inner_conns.remove(&handle).is_some()
+    }
+
+    /// Returns when the last message was received on the inner
+    /// CryptoAuth session `handle` (including `ka` keepalives), or None
+    /// if there is no such session. See `SwitchConfig::session_ttl`.
+    pub fn session_last_seen(&self, handle: u32) -> Option<Instant> {
+        self.last_seen.get(&handle).cloned()
+    }
+
+    /// Returns whether a switch reported, with a control error, that
+    /// it could not forward a packet sent with this label.
+    pub fn is_route_dead(&self, path: &Label) -> bool {
+        self.dead_routes.contains(path)
+    }
+
+    /// Remembers a path as dead, forgetting the oldest dead path if
+    /// there are too many.
+    fn mark_route_dead(&mut self, path: Label) {
+        if !self.dead_routes.insert(path) {
+            return
+        }
+        self.dead_routes_order.push_back(path);
+        if self.dead_routes_order.len() > MAX_DEAD_ROUTES {
+            let oldest = self.dead_routes_order.pop_front().unwrap(); // Not empty
+            self.dead_routes.remove(&oldest);
+        }
+    }
+
+    /// Returns the label to reach `node`, discovered through the router
+    /// on the inner session `via` (eg. in its response to a `gp` query):
+    /// the path to that router, spliced with the path of the node
+    /// relative to it.
+    ///
+    /// Returns None if there is no such session, or if the spliced label
+    /// does not fit in 64 bits.
+    pub fn label_to(&self, via: u32, node: &NodeData) -> Option<Label> {
+        let via_path = match self.inner_conns.get(&via) {
+            Some(&(path, _)) => path,
+            None => return None,
+        };
+        splice(&node.path, &via_path).ok()
+    }
+
+    /// Returns `label`, encoded with our scheme, with its second director
+    /// converted to the encoding scheme announced by the router on the
+    /// inner session `peer` (see `operation::convert_label`): the first
+    /// one is consumed by this switch, and the second one by the switch
+    /// of that router.
+    ///
+    /// The label is returned unchanged if that router did not announce
+    /// a scheme, or uses the same scheme as us, or if the label ends
+    /// with its first director.
+    pub fn prepare_label_for(&self, label: &Label, peer: u32) -> Result<Label, ConvertError> {
+        let our_scheme = EncodingScheme::from_iter(vec![ENCODING_FORM].iter());
+        let peer_scheme = match self.peer_schemes.get(&peer) {
+            Some(scheme) if *scheme != our_scheme => scheme,
+            _ => return Ok(*label),
+        };
+        let director_length = ENCODING_FORM.prefix_length + ENCODING_FORM.bit_count;
+        let bits = u64_from_label(*label);
+        let rest = bits >> director_length;
+        if rest == 0 {
+            return Ok(*label)
+        }
+        let rest = u64_from_label(try!(convert_label(&label_from_u64(rest), &our_scheme, peer_scheme)));
+        if rest.leading_zeros() < director_length as u32 {
+            return Err(ConvertError::TooLong)
+        }
+        Ok(label_from_u64((rest << director_length) | (bits & ((0b1u64 << director_length) - 1))))
+    }
+
+    /// Adds nodes to the ones known by this switch, eg. the ones
+    /// returned by `export_nodes` before a restart.
+    ///
+    /// This does not open sessions with them.
+    pub fn import_nodes(&mut self, nodes: &[NodeData]) {
+        for node in nodes {
+            self.known_nodes.replace(node.clone());
+        }
+    }
+
+    /// Sometimes (random) sends a switch as a reply to the packet.
+    fn random_send_switch_ping(&mut self, switch_packet: &SwitchPacket, out: &mut Vec<(u8, SwitchPacket)>) {
+        if self.config.proactive_discovery && rand::thread_rng().next_u32() > 0xafffffff {
+            let ping = self.control_packet_builder().ping();
+            let packet_response = SwitchPacket::new_reply(&switch_packet, SwitchPayload::Control(ping));
+            self.send(packet_response, SELF_INTERFACE, out);
+        }
+    }
+
+    /// Send a packet to the appropriate interface.
+    ///
+    /// Packets forwarded to a peer are pushed to `out`, along with the id
+    /// of the interface they should be sent through.
+    fn send(&mut self, mut packet: SwitchPacket, from_interface: u8, out: &mut Vec<(u8, SwitchPacket)>) {
+        // Logically advance the packet through an interface.
+        let reversed_origin_iface = reverse_director(from_interface as Director, ENCODING_FORM.bit_count);
+        let routing_decision = packet.switch(ENCODING_FORM.bit_count, &reversed_origin_iface);
+        match routing_decision {
+            Ok(RoutingDecision::SelfInterface(_)) => {
+                // Packet is sent to myself
+                self.on_self_interface_switch_packet(&packet, out);
+            }
+            Ok(RoutingDecision::Forward(iface_id)) => {
+                // Packet is sent to a peer.
+                out.push((iface_id as u8, packet));
+            }
+            Ok(RoutingDecision::LoopRoute(_)) => {
+                // Packet would be sent back to the peer it came from.
+                self.dropped_packets += 1;
+            }
+            Err(_) => {
+                // The label is exhausted (or the interface id does not
+                // fit in a director).
+                self.dropped_packets += 1;
+            }
+        }
+    }
+
+    /// Wraps packets to forward (eg. the ones returned by `handle_messages`
+    /// or `InterfaceMap::forward`), along with the id of their interface,
+    /// with the outer CryptoAuth session of the peer of that interface.
+    ///
+    /// Returns the datagrams to send, along with the address of the peer
+    /// they should be sent to. Packets for an interface which does not
+    /// exist (anymore) are dropped.
+    pub fn wrap_for_interfaces(&mut self, packets: Vec<(u8, SwitchPacket)>) -> Vec<(SocketAddr, Vec<u8>)> {
+        let now = Instant::now();
+        let packets = packets.into_iter().map(|(iface_id, packet)| (now, iface_id, packet)).collect();
+        self.wrap_pending(packets)
+    }
+
+    /// Same as `wrap_for_interfaces`, for packets first sent at the
+    /// given instant.
+    fn wrap_pending(&mut self, packets: Vec<(Instant, u8, SwitchPacket)>) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut datagrams = Vec::new();
+        for (sent_at, iface_id, packet) in packets {
+            let wrapped = match self.interfaces.iter_mut().find(|interface| interface.id == iface_id) {
+                Some(interface) => {
+                    let wrapped = interface.ca_session.wrap_message(&packet.raw);
+                    wrapped.into_iter().map(|datagram| (interface.addr, datagram)).collect::<Vec<_>>()
+                }
+                None => {
+                    self.dropped_packets += 1;
+                    continue
+                }
+            };
+            if wrapped.is_empty() {
+                // The session cannot send it yet; keep it for the
+                // next upkeep, if there is room left.
+                if self.pending_packets.len() < MAX_PENDING_PACKETS {
+                    self.pending_packets.push((sent_at, iface_id, packet));
+                }
+                else {
+                    self.dropped_packets += 1;
+                }
+            }
+            datagrams.extend(wrapped);
+        }
+        datagrams
+    }
+
+    /// Sends a route packet to a router through the inner CryptoAuth
+    /// session identified by `handle`, as a reply to `switch_packet`.
+    fn send_route_packet(&mut self, switch_packet: &SwitchPacket, handle: u32, route_packet: RoutePacket, out: &mut Vec<(u8, SwitchPacket)>) {
+        let data_packet = DataPacket::new(1, &DataPayload::RoutePacket(route_packet));
+        let responses: Vec<_> = match self.inner_conns.get_mut(&handle) {
+            Some(&mut (_path, ref mut inner_conn)) => {
+                let tmp = inner_conn.wrap_message_immediately(&data_packet.raw);
+                tmp.into_iter().map(|r| make_reply(&switch_packet, r, &inner_conn)).collect()
+            },
+            None => {
+                // The session was closed.
+                self.dropped_packets += 1;
+                return
+            }
+        };
+        for response in responses {
+            match response {
+                Some(response) => self.send(response, SELF_INTERFACE, out),
+                None => self.dropped_packets += 1,
+            }
+        }
+    }
+
+    /// Reply to `gp` queries by sending a list of my peers.
+    fn reply_getpeers(&mut self, switch_packet: &SwitchPacket, route_packet: &RoutePacket, handle: u32, out: &mut Vec<(u8, SwitchPacket)>) {
+        match self.cached_getpeers_response(route_packet, handle) {
+            Ok(route_packet) => self.send_route_packet(switch_packet, handle, route_packet, out),
+            Err(_) => {
+                // A node cannot be encoded; this is a bug, but it must
+                // not bring the switch down.
+                self.dropped_packets += 1;
+            }
+        }
+    }
+
+    /// Same as `getpeers_response`, but reuses a response built less than
+    /// `response_cache_ttl` ago for the same query, if any.
+    fn cached_getpeers_response(&mut self, route_packet: &RoutePacket, handle: u32) -> Result<RoutePacket, String> {
+        let ttl = match self.config.response_cache_ttl {
+            Some(ttl) => ttl,
+            None => return self.getpeers_response(route_packet, handle),
+        };
+        let now = Instant::now();
+        // Responses are built in order, so the expired ones come first.
+        while let Some(&(built, _)) = self.response_cache_order.front() {
+            if now.duration_since(built) < ttl {
+                break
+            }
+            let (_, key) = self.response_cache_order.pop_front().unwrap();
+            self.response_cache.remove(&key);
+        }
+        let key = (route_packet.query.clone().unwrap_or_default(), handle);
+        let mut response = match self.response_cache.get(&key) {
+            Some(response) => response.clone(),
+            None => {
+                let response = try!(self.getpeers_response(route_packet, handle));
+                if self.response_cache_order.len() >= MAX_CACHED_RESPONSES {
+                    // Full; make room by dropping the oldest response.
+                    let (_, oldest) = self.response_cache_order.pop_front().unwrap();
+                    self.response_cache.remove(&oldest);
+                }
+                self.response_cache.insert(key.clone(), response.clone());
+                self.response_cache_order.push_back((now, key));
+                response
+            }
+        };
+        response.transaction_id = route_packet.transaction_id.clone();
+        Ok(response)
+    }
+
+    /// Builds the response to a `gp` query received from the inner
+    /// session `handle`: myself, and my peers other than the querying one.
+    /// This is what the switch replies to `gp` queries; `handle` does not
+    /// have to be an existing session.
+    ///
+    /// The list always contains at least myself, even if I have
+    /// no other peer. If the encoded response would not fit in
+    /// `payload_mtu`, peers are left out of it and `more` is set.
+    ///
+    /// Fails if a node cannot be encoded, which does not happen as long
+    /// as all versions fit in a byte.
+    pub fn getpeers_response(&self, route_packet: &RoutePacket, handle: u32) -> Result<RoutePacket, String> {
+        // The querying peer reached us through a director of the only
+        // form of our scheme.
+        let encoding_scheme = EncodingScheme::from_iter(vec![ENCODING_FORM].iter());
+        let encoding_index = encoding_scheme.index_of(&ENCODING_FORM).unwrap();
+
+        let mut nodes = Vec::new();
+        {
+            // Add myself. My path has to be the self route, or routers
+            // would not be able to reach me through it.
+            debug_assert!(is_self_route(&SELF_LABEL, &encoding_scheme));
+            nodes.push(NodeData {
+                public_key: key_bytes(&self.my_pk),
+                path: SELF_LABEL,
+                version: ::std::cmp::min(self.config.version as u64, MAX_NODE_VERSION),
+            });
+        }
+        for (peer_handle, &(path, ref inner_conn)) in self.inner_conns.iter() {
+            if *peer_handle != handle {
+                // If the peer is not the one asking for the list of peers,
+                // add it to the list.
+                nodes.push(NodeData {
+                    public_key: key_bytes(inner_conn.their_pk()),
+                    path: path,
+                    version: self.peer_version(*peer_handle),
+                });
+            }
+        }
+        // TODO: only send the peers closest to the specified target address.
+
+        let build = |nodes: Vec<NodeData>, more: bool| -> Result<RoutePacket, String> {
+            let builder = try!(self.route_packet_builder(route_packet.transaction_id.clone())
+                    .nodes_vec(nodes));
+            let builder = builder
+                    .encoding_index(encoding_index as i64)
+                    .encoding_scheme(encoding_scheme.clone());
+            Ok(if more { builder.more(1) } else { builder }.finalize())
+        };
+        let response = try!(build(nodes.clone(), false));
+        let length = response.clone().encode().len();
+        if nodes.len() <= 1 || length <= self.payload_mtu() {
+            return Ok(response)
+        }
+        // Too large; keep as many peers as fit along with `more` (but
+        // never drop myself). The rest of the packet does not grow when
+        // there are fewer nodes (length prefixes only get shorter), so
+        // this is computed in one go.
+        let overhead = length - nodes.len() * NODE_ENCODED_LENGTH + MORE_ENCODED_LENGTH;
+        let max_nodes = self.payload_mtu().saturating_sub(overhead) / NODE_ENCODED_LENGTH;
+        nodes.truncate(::std::cmp::max(max_nodes, 1));
+        build(nodes, true)
+    }
+
+    /// Reply to `pn` queries, echoing their transaction id.
+    fn reply_ping(&mut self, switch_packet: &SwitchPacket, route_packet: &RoutePacket, handle: u32, out: &mut Vec<(u8, SwitchPacket)>) {
+        let route_packet = self.route_packet_builder(route_packet.transaction_id.clone())
+                .finalize();
+        self.send_route_packet(switch_packet, handle, route_packet, out);
+    }
+
+    /// Sometimes (random) sends a `gp` query.
+    fn random_send_getpeers(&mut self, reply_to: &SwitchPacket, handle: u32, out: &mut Vec<(u8, SwitchPacket)>) {
+        if self.config.proactive_discovery && rand::thread_rng().next_u32() > 0xafffffff {
+            let encoding_scheme = EncodingScheme::from_iter(vec![ENCODING_FORM].iter());
+            let encoding_index = encoding_scheme.index_of(&ENCODING_FORM).unwrap();
+            let route_packet = self.route_packet_builder(b"blah".to_vec())
+                    .route_query(RouteQuery::GetPeers)
+                    .encoding_index(encoding_index as i64)
+                    .encoding_scheme(encoding_scheme)
+                    .target_address(vec![0, 0, 0, 0, 0, 0, 0, 0])
+                    .finalize();
+            self.send_route_packet(reply_to, handle, route_packet, out);
+        }
+    }
+
+    /// Calls `on_inner_ca_message` on each message unwrapped from
+    /// a packet of the end-to-end session `handle`, until the session
+    /// is closed (eg. because the router is too old); the following
+    /// messages are dropped.
+    fn on_inner_ca_messages(&mut self, switch_packet: &SwitchPacket, handle: u32, ca_messages: Vec<Vec<u8>>, out: &mut Vec<(u8, SwitchPacket)>) {
+        for ca_message in ca_messages {
+            if !self.inner_conns.contains_key(&handle) {
+                self.dropped_packets += 1;
+                continue
+            }
+            self.on_inner_ca_message(switch_packet, handle, ca_message, out)
+        }
+    }
+
+    /// Called when a CryptoAuth message is received through an end-to-end
+    /// session.
+    fn on_inner_ca_message(&mut self, switch_packet: &SwitchPacket, handle: u32, ca_message: Vec<u8>, out: &mut Vec<(u8, SwitchPacket)>) {
+        self.last_seen.insert(handle, Instant::now());
+        let data_packet = DataPacket { raw: ca_message };
+
+        // If it is a query, reply to it.
+        let payload = match data_packet.payload() {
+            Ok(payload) => payload,
+            Err(_) => {
+                // Unknown content type, or undecodable route packet.
+                self.dropped_packets += 1;
+                return
+            }
+        };
+        match payload {
+            DataPayload::RoutePacket(route_packet) => {
+                if route_packet.path_contains(&key_bytes(&self.my_pk)) {
+                    // We are in the path vector, so the packet looped.
+                    self.dropped_packets += 1;
+                    return
+                }
+                if route_packet.protocol_version < self.config.min_peer_version as i64 {
+                    // Too old to talk with.
+                    self.remove_session(handle);
+                    self.dropped_packets += 1;
+                    return
+                }
+                if let Some(version) = route_packet.protocol_version() {
+                    // Versions we cannot announce in `gp` responses are
+                    // recorded as the highest one we can.
+                    self.peer_versions.insert(handle, ::std::cmp::min(version, MAX_NODE_VERSION));
+                }
+                if let Some(ref scheme) = route_packet.encoding_scheme {
+                    if !route_packet.nodes_match_scheme() {
+                        // The router announces paths whose first director
+                        // cannot be decoded with the scheme it announces
+                        // along with them; it is buggy or lying.
+                        self.dropped_packets += 1;
+                        return
+                    }
+                    if scheme.is_sane() {
+                        self.peer_schemes.insert(handle, scheme.clone());
+                    }
+                }
+                match route_packet.route_query() {
+                    Some(RouteQuery::GetPeers) => self.reply_getpeers(switch_packet, &route_packet, handle, out),
+                    Some(RouteQuery::FindNode) => {
+                        if route_packet.find_node_target().is_none() {
+                            // Nothing to look for.
+                            self.dropped_packets += 1;
+                            return
+                        }
+                        // The only nodes a switch knows are its peers,
+                        // so this is answered like `gp`.
+                        self.reply_getpeers(switch_packet, &route_packet, handle, out)
+                    },
+                    Some(RouteQuery::Ping) => self.reply_ping(switch_packet, &route_packet, handle, out),
+                    Some(RouteQuery::Keepalive) => {
+                        // Only refreshes the session, nothing to send.
+                        return
+                    },
+                    Some(query) => {
+                        if let Some(ref mut hook) = self.unknown_query_hook {
+                            hook(query.as_str(), &route_packet)
+                        }
+                    },
+                    None => (),
+                }
+            }
+        }
+
+        self.random_send_getpeers(switch_packet, handle, out)
+    }
+
+    /// Called when a switch packet is sent to the self interface
+    fn on_self_interface_switch_packet(&mut self, switch_packet: &SwitchPacket, out: &mut Vec<(u8, SwitchPacket)>) {
+        match switch_packet.payload() {
+            Ok(SwitchPayload::Control(ping @ ControlPacket::Ping { .. })) => {
+                // If it is a ping packet, just reply to it.
+                let control_response = self.control_packet_builder().pong_to(&ping).unwrap();
+                let packet_response = SwitchPacket::new_reply(switch_packet, SwitchPayload::Control(control_response));
+                self.send(packet_response, SELF_INTERFACE, out);
+
+                self.random_send_switch_ping(switch_packet, out);
+            },
+            Ok(SwitchPayload::Control(ControlPacket::Pong { .. })) => {
+                // Nothing to do; we only send pings to keep the link
+                // alive.
+            },
+            Ok(SwitchPayload::Control(ControlPacket::Error { cause, .. })) => {
+                // A switch could not handle a packet. The cause starts
+                // with the switch header of that packet, as it reached
+                // that switch: the directors consumed on the way were
+                // replaced with the return path, and counted in the
+                // label shift. Undo the shift to find the known paths
+                // the packet may have been sent on, mark them as dead,
+                // and forget the nodes using them.
+                if cause.len() >= 12 {
+                    let mut label = [0u8; 8];
+                    label.copy_from_slice(&cause[0..8]);
+                    let label_shift = cause[9] & 0b00111111;
+                    let dead_paths = if label_shift == 0 {
+                        vec![label]
+                    }
+                    else {
+                        let remaining = u64_from_label(label) & ((0b1u64 << (64 - label_shift)) - 1);
+                        self.known_nodes.iter()
+                                .map(|node| node.path)
+                                .filter(|path| u64_from_label(*path) >> label_shift == remaining)
+                                .collect()
+                    };
+                    for path in dead_paths {
+                        self.known_nodes.retain(|node| node.path != path);
+                        self.mark_route_dead(path);
+                    }
+                }
+                else {
+                    self.dropped_packets += 1;
+                }
+            },
+            Ok(SwitchPayload::CryptoAuthHandshake(handshake)) => {
+                // If it is a CryptoAuth handshake packet (ie. if someone is
+                // connecting to us), create a new session for this node.
+                // All CA handshake we receive will be sessions started by
+                // other peers, because this switch never starts sessions
+                // (routers do, not switches).
+                if handshake.len() < HANDSHAKE_HEADER_LENGTH {
+                    // Too short to be a hello.
+                    self.dropped_packets += 1;
+                    return
+                }
+                let handshake = HandshakePacket { raw: handshake };
+                if let Some(&handle) = self.inner_handshakes.get(&handshake.sender_perm_pub_key()) {
+                    // This router already has a session (the hello was
+                    // re-sent, eg. because it did not get our key packet),
+                    // so let this session handle it.
+                    let inner_packets = match self.inner_conns.get_mut(&handle) {
+                        Some(&mut (ref mut path, ref mut inner_conn)) => {
+                            match inner_conn.unwrap_message(handshake.raw) {
+                                Ok(inner_packets) => {
+                                    *path = switch_packet.reply_label();
+                                    inner_packets
+                                },
+                                Err(_) => {
+                                    self.dropped_packets += 1;
+                                    return
+                                }
+                            }
+                        },
+                        None => {
+                            self.dropped_packets += 1;
+                            return
+                        }
+                    };
+                    self.on_inner_ca_messages(switch_packet, handle, inner_packets, out);
+                    return
+                }
+                let mut handle;
+                loop {
+                    handle = self.handle_allocator.next_handle();
+                    if handle >= MIN_SESSION_HANDLE && handle != CONTROL_HANDLE && !self.inner_conns.contains_key(&handle) {
+                        break
+                    }
+                };
+                let (inner_conn, inner_packet) = match Wrapper::new_incoming_connection(self.my_pk, self.my_sk.clone(), Credentials::None, None, Some(handle), handshake.raw.clone()) {
+                    Ok(opened) => opened,
+                    Err(_) => {
+                        // Not a valid hello.
+                        self.dropped_packets += 1;
+                        return
+                    }
+                };
+                let path = switch_packet.reply_label();
+                self.inner_conns.insert(handle, (path, inner_conn));
+                self.inner_handshakes.insert(handshake.sender_perm_pub_key(), handle);
+                self.on_inner_ca_message(switch_packet, handle, inner_packet, out);
+                self.random_send_switch_ping(switch_packet, out);
+            },
+            Ok(SwitchPayload::CryptoAuthData(handle, ca_message)) => {
+                // If it is a CryptoAuth data packet, first read the session
+                // handle to know which CryptoAuth session to use to
+                // decrypt it.
+                let inner_packets = match self.inner_conns.get_mut(&handle) {
+                    Some(&mut (_path, ref mut inner_conn)) => {
+                        match inner_conn.unwrap_message(ca_message) {
+                            Ok(inner_packets) => inner_packets,
+                            Err(_) => {
+                                // Replayed or corrupted packet.
+                                self.dropped_packets += 1;
+                                return
+                            }
+                        }
+                    }
+                    None => {
+                        // No session with this handle (it may have been
+                        // forged by the peer), so we cannot decrypt
+                        // the packet.
+                        self.dropped_packets += 1;
+                        return
+                    }
+                };
+                self.on_inner_ca_messages(switch_packet, handle, inner_packets, out)
+            }
+            Err(_) => {
+                // Control packet which cannot be decoded.
+                self.dropped_packets += 1;
+            }
+            Ok(SwitchPayload::Control(_)) => {
+                // Key pings and pongs are not supported.
+                self.dropped_packets += 1;
+            }
+        }
+    }
+
+    // Find what interface a UDP packet is coming from, using its emitted
+    // IP address. Returns None if the packet does not open a session
+    // with an unknown address, or there is no director left for it.
+    fn get_incoming_iface_and_open(&mut self, from_addr: SocketAddr, buf: Vec<u8>) -> Option<(&Interface, Vec<Vec<u8>>)> {
+        let mut iface_exists = false;
+        for candidate_interface in self.interfaces.iter_mut() {
+            if candidate_interface.addr == from_addr {
+                iface_exists = true;
+                break
+            }
+        }
+
+        if iface_exists {
+            // Workaround for https://github.com/rust-lang/rust/issues/38614
+            for candidate_interface in self.interfaces.iter_mut() {
+                if candidate_interface.addr == from_addr {
+                    let messages = match candidate_interface.ca_session.unwrap_message(buf) {
+                        Ok(messages) => messages,
+                        Err(_) => {
+                            // Eg. a repeated handshake packet after the
+                            // session is set up, which happens when both
+                            // ends start it at once. CryptoAuth already
+                            // broke the tie, so it is safe to ignore.
+                            self.dropped_packets += 1;
+                            Vec::new()
+                        }
+                    };
+                    return Some((candidate_interface, messages));
+                }
+            }
+            panic!("The impossible happened.");
+        }
+        else {
+            // Not a known address
+            let (ca_session, message) = match Wrapper::new_incoming_connection(self.my_pk.clone(), self.my_sk.clone(), Credentials::None, Some(self.allowed_peers.clone()), None, buf) {
+                Ok(opened) => opened,
+                Err(_) => {
+                    // Not a valid hello, or not from an allowed peer.
+                    self.dropped_packets += 1;
+                    return None
+                }
+            };
+            let position = self.interfaces.iter().position(|iface| iface.ca_session.their_pk() == ca_session.their_pk());
+            match position {
+                Some(position) => {
+                    // A known peer reconnecting (possibly from an other
+                    // address); reuse its director instead of allocating
+                    // a new one.
+                    let interface = &mut self.interfaces[position];
+                    interface.ca_session = Box::new(ca_session);
+                    interface.addr = from_addr;
+                    Some((interface, vec![message]))
+                }
+                None => {
+                    // Not a known peer; create an interface
+                    let next_iface_id = match (0..0b1000).filter(|candidate| self.interfaces.iter().find(|iface| iface.id == *candidate).is_none()).next() {
+                        Some(iface_id) => iface_id,
+                        None => {
+                            // All directors are in use.
+                            self.dropped_packets += 1;
+                            return None
+                        }
+                    };
+                    let new_iface = Interface { id: next_iface_id, ca_session: Box::new(ca_session), addr: from_addr };
+                    self.interfaces.push(new_iface);
+                    let interface = self.interfaces.last_mut().unwrap();
+                    Some((interface, vec![message]))
+                }
+            }
+        }
+    }
+
+    /// Called when a UDP packet is received.
+    ///
+    /// Returns the datagrams to send, along with the address of the peer
+    /// they should be sent to.
+    ///
+    /// If this switch and a peer it has an interface for start a session
+    /// with each other at once, the hello of the one with the lower public
+    /// key wins (this is done by CryptoAuth, as in cjdns), and the
+    /// interface keeps a single session. Handshake packets the session
+    /// rejects are dropped.
+    pub fn on_outer_ca_message(&mut self, from_addr: SocketAddr, buf: Vec<u8>) -> Vec<(SocketAddr, Vec<u8>)> {
+        let (iface_id, messages) = match self.get_incoming_iface_and_open(from_addr, buf) {
+            Some((interface, messages)) => (interface.id, messages),
+            None => return Vec::new(),
+        };
+        let mut out = Vec::new();
+        for action in self.handle_messages(iface_id, messages) {
+            if let Action::Forward(iface_id, packet) = action {
+                out.push((iface_id, packet));
+            }
+        }
+        self.wrap_for_interfaces(out)
+    }
+
+    /// Handles switch packets received (and decrypted) from the peer
+    /// on interface `iface_id`, in order.
+    ///
+    /// A message which is not a valid switch packet does not prevent
+    /// the following ones from being handled: it is dropped, and
+    /// reported as `Action::Drop`. Actions are returned in the same
+    /// order as the messages causing them.
+    pub fn handle_messages(&mut self, iface_id: u8, messages: Vec<Vec<u8>>) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for (i, message) in messages.into_iter().enumerate() {
+            let packet = match parse_outer(message) {
+                OuterEvent::Malformed(_) => {
+                    self.dropped_packets += 1;
+                    actions.push(Action::Drop(i));
+                    continue
+                },
+                OuterEvent::Control(packet) |
+                OuterEvent::Handshake(packet) |
+                OuterEvent::SwitchData(packet) => packet,
+            };
+            let mut out = Vec::new();
+            self.send(packet, iface_id, &mut out);
+            actions.extend(out.into_iter().map(|(iface_id, packet)| Action::Forward(iface_id, packet)));
+        }
+        actions
+    }
+
+    /// Passes control to the outer CryptoAuth sessions, so they can
+    /// perform internal maintainance.
+    ///
+    /// Returns the datagrams to send, along with the address of the peer
+    /// they should be sent to.
+    pub fn upkeep(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        if let Some(session_ttl) = self.config.session_ttl {
+            let now = Instant::now();
+            let idle_handles = self.last_seen.iter()
+                    .filter(|&(_, &last_seen)| now.duration_since(last_seen) >= session_ttl)
+                    .map(|(&handle, _)| handle)
+                    .collect::<Vec<_>>();
+            for handle in idle_handles {
+                self.remove_session(handle);
+            }
+        }
+        let mut out = Vec::new();
+        for interface in self.interfaces.iter_mut() {
+            for packet in interface.ca_session.upkeep() {
+                out.push((interface.addr, packet));
+            }
+        }
+        // Retry the packets the sessions could not wrap before, unless
+        // they have been waiting for too long.
+        let now = Instant::now();
+        let ttl = self.config.pending_packet_ttl;
+        let pending = ::std::mem::replace(&mut self.pending_packets, Vec::new());
+        let (pending, expired): (Vec<_>, Vec<_>) = pending.into_iter()
+                .partition(|&(sent_at, _, _)| now.duration_since(sent_at) < ttl);
+        self.dropped_packets += expired.len() as u64;
+        out.append(&mut self.wrap_pending(pending));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use fcp_cryptoauth;
+    use fcp_cryptoauth::wrapper::*;
+    use control::ErrorType;
+    use switch_packet::PacketType;
+    use operation::label_from_u64;
+    use super::*;
+
+    fn new_switch_core() -> SwitchCore {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        SwitchCore::new(vec![], my_pk, my_sk, HashMap::new())
+    }
+
+    /// Opens an inner CryptoAuth session with the switch, as a router
+    /// with the given keys behind interface 0b011 would, with `message`
+    /// piggybacked on the handshake.
+    /// Returns the router's end of the session, and the packets sent by
+    /// the switch.
+    fn connect_router(switch: &mut SwitchCore, router_keys: &(PublicKey, SecretKey), message: &[u8]) -> (Wrapper<()>, Vec<(u8, SwitchPacket)>) {
+        let mut router = Wrapper::new_outgoing_connection(router_keys.0, router_keys.1.clone(), switch.my_pk, Credentials::None, None, (), Some(1234));
+        let handshake = router.wrap_message_immediately(message).remove(0);
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthHandshake(handshake));
+        let mut out = Vec::new();
+        switch.send(packet, 0b011, &mut out);
+        (router, out)
+    }
+
+    /// Decrypts the first packet sent by the switch to a router,
+    /// and decodes the route packet in it.
+    fn open_route_packet(router: &mut Wrapper<()>, out: &[(u8, SwitchPacket)]) -> RoutePacket {
+        let &(iface_id, ref packet) = &out[0];
+        assert_eq!(iface_id, 0b011);
+        let ca_message = match packet.payload() {
+            Ok(SwitchPayload::CryptoAuthHandshake(ca_message)) => ca_message,
+            Ok(SwitchPayload::CryptoAuthData(1234, ca_message)) => ca_message,
+            p => panic!("Unexpected payload: {:?}", p),
+        };
+        let mut messages = router.unwrap_message(ca_message).unwrap();
+        match (DataPacket { raw: messages.remove(0) }).payload().unwrap() {
+            DataPayload::RoutePacket(route_packet) => route_packet,
+        }
+    }
+
+    /// Starts an outer CryptoAuth session with the switch, from `addr`,
+    /// and returns the interface the switch allocated for it.
+    fn connect_peer(switch: &mut SwitchCore, credentials: &Credentials, peer_keys: &(PublicKey, SecretKey), addr: SocketAddr) -> Interface {
+        let mut peer = Wrapper::new_outgoing_connection(peer_keys.0, peer_keys.1.clone(), switch.my_pk, credentials.clone(), None, "switch".to_owned(), None);
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        let hello = peer.wrap_message_immediately(&packet.raw).remove(0);
+        switch.on_outer_ca_message(addr, hello);
+        let interface = switch.interfaces.iter().find(|iface| iface.addr == addr).unwrap();
+        Interface { id: interface.id, ca_session: Box::new(peer), addr: addr }
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        fcp_cryptoauth::init();
+        let credentials = Credentials::LoginPassword { login: b"foo".to_vec(), password: b"bar".to_vec() };
+        let mut allowed_peers = HashMap::new();
+        allowed_peers.insert(credentials.clone(), "my peer".to_owned());
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let addr_a: SocketAddr = "[::1]:2001".parse().unwrap();
+        let addr_b: SocketAddr = "[::1]:2002".parse().unwrap();
+        let (pk_a, sk_a) = gen_keypair();
+        let (pk_b, sk_b) = gen_keypair();
+
+        // Both switches know each other, and start a session at once.
+        let a_to_b = Wrapper::new_outgoing_connection(pk_a, sk_a.clone(), pk_b, credentials.clone(), Some(allowed_peers.clone()), "my peer".to_owned(), None);
+        let b_to_a = Wrapper::new_outgoing_connection(pk_b, sk_b.clone(), pk_a, credentials.clone(), Some(allowed_peers.clone()), "my peer".to_owned(), None);
+        let mut a = SwitchCore::with_config(vec![Interface { id: 0b011, ca_session: Box::new(a_to_b), addr: addr_b }], pk_a, sk_a, allowed_peers.clone(), config.clone());
+        let mut b = SwitchCore::with_config(vec![Interface { id: 0b101, ca_session: Box::new(b_to_a), addr: addr_a }], pk_b, sk_b, allowed_peers, config);
+        let mut to_b = a.upkeep();
+        let mut to_a = b.upkeep();
+        assert_eq!(to_b.len(), 1);
+        assert_eq!(to_a.len(), 1);
+
+        // The hellos cross; exchange datagrams until the handshake is over.
+        for _ in 0..4 {
+            let mut next_to_a = Vec::new();
+            let mut next_to_b = Vec::new();
+            for (addr, datagram) in to_b {
+                assert_eq!(addr, addr_b);
+                next_to_a.extend(b.on_outer_ca_message(addr_a, datagram));
+            }
+            for (addr, datagram) in to_a {
+                assert_eq!(addr, addr_a);
+                next_to_b.extend(a.on_outer_ca_message(addr_b, datagram));
+            }
+            next_to_a.extend(b.upkeep());
+            next_to_b.extend(a.upkeep());
+            to_a = next_to_a;
+            to_b = next_to_b;
+        }
+
+        // A single session survived on each side.
+        assert_eq!(a.interfaces.len(), 1);
+        assert_eq!(b.interfaces.len(), 1);
+
+        // As in cjdns, the hello of the peer with the lower key won: its
+        // session is set up, and the other one's completes on the first
+        // data packet it receives. In the meantime, it kept repeating its
+        // key packet, which the winner ignored.
+        let (winner, loser, winner_addr) = if pk_a < pk_b { (&mut a, &mut b, addr_a) } else { (&mut b, &mut a, addr_b) };
+        assert_eq!(loser.dropped_packets, 0);
+        // The loser's repeated key packets of the last two rounds.
+        assert_eq!(winner.dropped_packets, 2);
+        assert_eq!(*winner.interfaces[0].ca_session.their_pk(), loser.my_pk);
+        assert_eq!(*loser.interfaces[0].ca_session.their_pk(), winner.my_pk);
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        let datagrams = winner.interfaces[0].ca_session.wrap_message(&packet.raw);
+        assert_eq!(datagrams.len(), 1);
+        let mut pongs = loser.on_outer_ca_message(winner_addr, datagrams[0].clone());
+        // The session of the loser was not complete yet when it had to
+        // wrap the pong, so the pong is sent on upkeep.
+        pongs.extend(loser.upkeep());
+        assert_eq!(pongs.len(), 1);
+        let messages = winner.interfaces[0].ca_session.unwrap_message(pongs[0].1.clone()).unwrap();
+        match SwitchPacket::parse(messages[0].clone()).unwrap().payload() {
+            Ok(SwitchPayload::Control(ControlPacket::Pong { .. })) => (),
+            p => panic!("Not a pong: {:?}", p),
+        }
+        assert_eq!(loser.dropped_packets, 0);
+
+        // Both ends of this session are established: none of them has
+        // handshake packets to repeat anymore.
+        assert!(winner.upkeep().is_empty());
+        assert!(loser.upkeep().is_empty());
+    }
+
+    #[test]
+    fn reuse_director_on_reconnect() {
+        fcp_cryptoauth::init();
+        let credentials = Credentials::LoginPassword { login: b"foo".to_vec(), password: b"bar".to_vec() };
+        let mut allowed_peers = HashMap::new();
+        allowed_peers.insert(credentials.clone(), "my peer".to_owned());
+        let (my_pk, my_sk) = gen_keypair();
+        let mut switch = SwitchCore::new(vec![], my_pk, my_sk, allowed_peers);
+
+        let addr1 = "[::1]:1001".parse().unwrap();
+        let addr2 = "[::1]:1002".parse().unwrap();
+        let addr3 = "[::1]:1003".parse().unwrap();
+        let peer_keys = gen_keypair();
+        let first = connect_peer(&mut switch, &credentials, &peer_keys, addr1);
+        let other = connect_peer(&mut switch, &credentials, &gen_keypair(), addr2);
+        assert!(first.id != other.id);
+        assert_eq!(switch.interfaces.len(), 2);
+
+        let reconnected = connect_peer(&mut switch, &credentials, &peer_keys, addr3);
+        assert_eq!(reconnected.id, first.id);
+        assert_eq!(switch.interfaces.len(), 2);
+        assert!(switch.interfaces.iter().find(|iface| iface.addr == addr1).is_none());
+    }
+
+    #[test]
+    fn drop_loop_route() {
+        let mut switch = new_switch_core();
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001_011], SwitchPayload::Control(ping));
+        let mut out = Vec::new();
+        switch.send(packet, 0b011, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(switch.dropped_packets(), 1);
+    }
+
+    #[test]
+    fn handle_messages_past_malformed() {
+        let mut switch = new_switch_core();
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        let messages = vec![packet.raw.clone(), vec![0, 0, 0, 0, 0, 0, 0, 0b001, 0], packet.raw.clone()];
+        let actions = switch.handle_messages(0b011, messages);
+
+        let mut pongs = 0;
+        let mut drops = Vec::new();
+        for action in actions {
+            match action {
+                Action::Forward(0b011, packet) => match packet.payload() {
+                    Ok(SwitchPayload::Control(ControlPacket::Pong { .. })) => pongs += 1,
+                    Ok(SwitchPayload::Control(ControlPacket::Ping { .. })) => (), // random keepalive
+                    p => panic!("Unexpected payload: {:?}", p),
+                },
+                Action::Drop(i) => {
+                    // Reported between the two pongs
+                    assert_eq!(pongs, 1);
+                    drops.push(i);
+                }
+                a => panic!("Unexpected action: {:?}", a),
+            }
+        }
+        assert_eq!(pongs, 2);
+        assert_eq!(drops, vec![1]);
+        assert_eq!(switch.dropped_packets(), 1);
+    }
+
+    #[test]
+    fn export_import_nodes() {
+        let mut switch = new_switch_core();
+        assert!(switch.export_nodes().is_empty());
+
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let router_keys = gen_keypair();
+        connect_router(&mut switch, &router_keys, &ping.raw);
+        let nodes = switch.export_nodes();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(&nodes[0].public_key[..], &(router_keys.0).0[..]);
+        assert_eq!(nodes[0].path, [0, 0, 0, 0, 0, 0, 0, 0b011]);
+
+        let other = NodeData { public_key: [42; 32], path: [0, 0, 0, 0, 0, 0, 0, 0b101], version: 18 };
+        let mut restarted = new_switch_core();
+        restarted.import_nodes(&nodes);
+        restarted.import_nodes(&[other.clone()]);
+        let mut expected = vec![nodes[0].clone(), other];
+        expected.sort();
+        let exported = restarted.export_nodes();
+        assert_eq!(exported, expected);
+        assert_eq!(exported.iter().map(|n| n.path).collect::<Vec<_>>(), expected.iter().map(|n| n.path).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn no_proactive_discovery() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        let route_ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let route_ping = DataPacket::new(1, &DataPayload::RoutePacket(route_ping));
+        // Random emissions happen about once every three packets.
+        for _ in 0..50 {
+            let actions = switch.handle_messages(0b011, vec![packet.raw.clone()]);
+            assert_eq!(actions.len(), 1); // The pong
+            let (_, out) = connect_router(&mut switch, &gen_keypair(), &route_ping.raw);
+            assert_eq!(out.len(), 1); // The route pong
+        }
+    }
+
+    #[test]
+    fn configured_version() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { version: 17, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        match switch.handle_messages(0b011, vec![packet.raw]).remove(0) {
+            Action::Forward(_, pong) => match pong.payload() {
+                Ok(SwitchPayload::Control(ControlPacket::Pong { version, .. })) => assert_eq!(version, 17),
+                p => panic!("Unexpected payload: {:?}", p),
+            },
+            a => panic!("Unexpected action: {:?}", a),
+        }
+
+        let route_ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let route_ping = DataPacket::new(1, &DataPayload::RoutePacket(route_ping));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &route_ping.raw);
+        assert_eq!(open_route_packet(&mut router, &out).protocol_version, 17);
+
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .finalize();
+        let response = switch.getpeers_response(&getpeers, 1234).unwrap();
+        assert_eq!(response.protocol_version, 17);
+        assert_eq!(response.read_nodes().unwrap()[0].version, 17);
+    }
+
+    #[test]
+    fn keepalive_refreshes_session() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        let (handle, _, _) = switch.sessions().next().unwrap();
+        let first_seen = switch.session_last_seen(handle).unwrap();
+
+        ::std::thread::sleep(::std::time::Duration::from_millis(2));
+        let keepalive = RoutePacketBuilder::new(18, b"ka txid".to_vec())
+                .route_query(RouteQuery::Keepalive)
+                .finalize();
+        let keepalive = DataPacket::new(1, &DataPayload::RoutePacket(keepalive));
+        let packet = SwitchPacket::from_ca_message(&[0, 0, 0, 0, 0, 0, 0, 0b001], Some(handle), vec![0, 0, 0, 4]).unwrap();
+        let mut out = Vec::new();
+        switch.on_inner_ca_message(&packet, handle, keepalive.raw, &mut out);
+        assert!(out.is_empty());
+        assert!(switch.session_last_seen(handle).unwrap() > first_seen);
+
+        assert!(switch.remove_session(handle));
+        assert_eq!(switch.session_last_seen(handle), None);
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, session_ttl: Some(Duration::from_millis(200)), ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+        let ping = RoutePacket::ping(18, b"ping txid".to_vec());
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        let (handle, _, _) = switch.sessions().next().unwrap();
+
+        // Keepalives keep the session open.
+        ::std::thread::sleep(Duration::from_millis(120));
+        let keepalive = DataPacket::new(1, &DataPayload::RoutePacket(RoutePacket::keepalive(18, b"ka txid".to_vec())));
+        let packet = SwitchPacket::from_ca_message(&[0, 0, 0, 0, 0, 0, 0, 0b001], Some(handle), vec![0, 0, 0, 4]).unwrap();
+        switch.on_inner_ca_message(&packet, handle, keepalive.raw, &mut Vec::new());
+        ::std::thread::sleep(Duration::from_millis(120));
+        switch.upkeep();
+        assert_eq!(switch.sessions().count(), 1);
+
+        // Until it is idle for too long.
+        ::std::thread::sleep(Duration::from_millis(250));
+        switch.upkeep();
+        assert_eq!(switch.sessions().count(), 0);
+        assert_eq!(switch.session_last_seen(handle), None);
+    }
+
+    #[test]
+    fn reject_old_peer() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, min_peer_version: 18, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+
+        let old_ping = RoutePacketBuilder::new(17, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let old_ping = DataPacket::new(1, &DataPayload::RoutePacket(old_ping));
+        let (_, out) = connect_router(&mut switch, &gen_keypair(), &old_ping.raw);
+        assert!(out.is_empty());
+        assert_eq!(switch.sessions().count(), 0);
+        assert_eq!(switch.dropped_packets, 1);
+
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        assert_eq!(open_route_packet(&mut router, &out).transaction_id, b"ping txid".to_vec());
+        assert_eq!(switch.sessions().count(), 1);
+
+        // Messages unwrapped along with an old one are dropped, as the
+        // session is closed.
+        let (handle, _, _) = switch.sessions().next().unwrap();
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .finalize();
+        let getpeers = DataPacket::new(1, &DataPayload::RoutePacket(getpeers));
+        let packet = SwitchPacket::from_ca_message(&[0, 0, 0, 0, 0, 0, 0, 0b001], Some(handle), vec![0, 0, 0, 4]).unwrap();
+        let mut out = Vec::new();
+        switch.on_inner_ca_messages(&packet, handle, vec![old_ping.raw, getpeers.raw], &mut out);
+        assert!(out.is_empty());
+        assert_eq!(switch.sessions().count(), 0);
+        assert_eq!(switch.dropped_packets, 3);
+    }
+
+    #[test]
+    fn drop_nodes_inconsistent_with_scheme() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+
+        // No form for directors ending with 0b00
+        let variable = EncodingScheme::from_iter(vec![
+            EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        ].iter());
+        let node = NodeData { public_key: [1; 32], path: [0, 0, 0, 0, 0, 0, 0, 0b0001_00], version: 18 };
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .nodes_vec(vec![node]).unwrap()
+                .encoding_scheme(variable.clone())
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let (_, out) = connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        assert!(out.is_empty());
+        assert_eq!(switch.dropped_packets, 1);
+        let (handle, _, _) = switch.sessions().next().unwrap();
+        assert_eq!(switch.peer_schemes.get(&handle), None);
+
+        // A path through switches with other schemes is accepted, as
+        // long as its first director is in the emitter's scheme.
+        let node = NodeData { public_key: [2; 32], path: label_from_u64(0b001_011_101_1), version: 18 };
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .nodes_vec(vec![node]).unwrap()
+                .encoding_scheme(variable.clone())
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        assert_eq!(open_route_packet(&mut router, &out).transaction_id, b"ping txid".to_vec());
+        assert_eq!(switch.dropped_packets, 1);
+        assert!(switch.peer_schemes.values().any(|scheme| *scheme == variable));
+    }
+
+    #[test]
+    fn duplicate_handshake() {
+        let mut switch = new_switch_core();
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let router_keys = gen_keypair();
+        let mut router = Wrapper::new_outgoing_connection(router_keys.0, router_keys.1.clone(), switch.my_pk, Credentials::None, None, (), Some(1234));
+        let handshake = router.wrap_message_immediately(&ping.raw).remove(0);
+        for _ in 0..2 {
+            let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthHandshake(handshake.clone()));
+            let mut out = Vec::new();
+            switch.send(packet, 0b011, &mut out);
+        }
+        assert_eq!(switch.sessions().count(), 1);
+
+        // A hello re-sent by a restarted router, with a fresh nonce and
+        // ephemeral key, does not open a second session either.
+        let mut router = Wrapper::new_outgoing_connection(router_keys.0, router_keys.1.clone(), switch.my_pk, Credentials::None, None, (), Some(1234));
+        let fresh_handshake = router.wrap_message_immediately(&ping.raw).remove(0);
+        assert!(fresh_handshake != handshake);
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthHandshake(fresh_handshake));
+        let mut out = Vec::new();
+        switch.send(packet, 0b011, &mut out);
+        assert_eq!(switch.sessions().count(), 1);
+        let response = open_route_packet(&mut router, &out);
+        assert_eq!(response.transaction_id, b"ping txid".to_vec());
+
+        // Once the session is closed, it opens a new one.
+        let (handle, _, _) = switch.sessions().next().unwrap();
+        assert!(switch.remove_session(handle));
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthHandshake(handshake));
+        switch.send(packet, 0b011, &mut Vec::new());
+        assert_eq!(switch.sessions().count(), 1);
+    }
+
+    #[test]
+    fn reply_find_node() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+
+        let query = RoutePacket::find_node(18, b"fn txid".to_vec(), vec![0xfc; 16]);
+        let query = DataPacket::new(1, &DataPayload::RoutePacket(query));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &query.raw);
+        let response = open_route_packet(&mut router, &out);
+        assert_eq!(response.transaction_id, b"fn txid".to_vec());
+        let nodes = response.read_nodes().unwrap();
+        assert_eq!(&nodes[0].public_key[..], &switch.my_pk.0[..]);
+
+        // Without a target, it is dropped.
+        let query = RoutePacketBuilder::new(18, b"fn txid".to_vec())
+                .route_query(RouteQuery::FindNode)
+                .finalize();
+        let query = DataPacket::new(1, &DataPayload::RoutePacket(query));
+        let (_, out) = connect_router(&mut switch, &gen_keypair(), &query.raw);
+        assert!(out.is_empty());
+        assert_eq!(switch.dropped_packets, 1);
+    }
+
+    #[test]
+    fn unknown_query_hook() {
+        use std::sync::{Arc, Mutex};
+        let mut switch = new_switch_core();
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        {
+            let queries = queries.clone();
+            switch.set_unknown_query_hook(move |query, packet| {
+                queries.lock().unwrap().push((query.to_owned(), packet.transaction_id.clone()))
+            });
+        }
+        let query = RoutePacketBuilder::new(18, b"foo txid".to_vec())
+                .query("foo".to_owned())
+                .finalize();
+        let query = DataPacket::new(1, &DataPayload::RoutePacket(query));
+        connect_router(&mut switch, &gen_keypair(), &query.raw);
+        assert_eq!(*queries.lock().unwrap(), vec![("foo".to_owned(), b"foo txid".to_vec())]);
+
+        // Handled queries do not fire it.
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        assert_eq!(queries.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn switch_core_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&new_switch_core());
+    }
+
+    #[test]
+    fn getpeers_response_fits_mtu() {
+        let mut switch = new_switch_core();
+        for handle in 0..100 {
+            let (their_pk, _) = gen_keypair();
+            let inner_conn = Wrapper::new_outgoing_connection(switch.my_pk, switch.my_sk.clone(), their_pk, Credentials::None, None, (), None);
+            switch.inner_conns.insert(handle + 4, ([0, 0, 0, 0, 0, 0, 0, 0b001_011], inner_conn));
+        }
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .finalize();
+        let response = switch.getpeers_response(&getpeers, 1234).unwrap();
+        let length = response.clone().encode().len();
+        assert!(length <= switch.payload_mtu());
+        // No room was left for an other node.
+        assert!(length + NODE_ENCODED_LENGTH > switch.payload_mtu());
+        assert!(response.has_more());
+        let nodes = response.read_nodes().unwrap();
+        assert!(nodes.len() > 1 && nodes.len() < 101);
+        assert_eq!(&nodes[0].public_key[..], &switch.my_pk.0[..]);
+
+        // Few peers: everything fits.
+        let switch = new_switch_core();
+        let response = switch.getpeers_response(&getpeers, 1234).unwrap();
+        assert!(!response.has_more());
+        assert_eq!(response.more, None);
+    }
+
+    #[test]
+    fn getpeers_response_cache() {
+        let mut switch = new_switch_core();
+        switch.config.response_cache_ttl = Some(Duration::from_secs(60));
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .target_address(vec![0; 16])
+                .finalize();
+        let response = switch.cached_getpeers_response(&getpeers, 1234).unwrap();
+        assert_eq!(response.read_nodes().unwrap().len(), 1);
+
+        // A new peer is not announced until the cached response expires,
+        // but the response still answers the new query.
+        let (their_pk, _) = gen_keypair();
+        let inner_conn = Wrapper::new_outgoing_connection(switch.my_pk, switch.my_sk.clone(), their_pk, Credentials::None, None, (), None);
+        switch.inner_conns.insert(5, ([0, 0, 0, 0, 0, 0, 0, 0b001_011], inner_conn));
+        let getpeers2 = RoutePacketBuilder::new(18, b"gp txid2".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .target_address(vec![0; 16])
+                .finalize();
+        let cached = switch.cached_getpeers_response(&getpeers2, 1234).unwrap();
+        assert_eq!(cached.read_nodes().unwrap().len(), 1);
+        assert_eq!(cached.transaction_id, b"gp txid2".to_vec());
+
+        // The target does not matter, as the response does not depend
+        // on it.
+        let getpeers3 = RoutePacketBuilder::new(18, b"gp txid3".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .target_address(vec![1; 16])
+                .finalize();
+        assert_eq!(switch.cached_getpeers_response(&getpeers3, 1234).unwrap().read_nodes().unwrap().len(), 1);
+        assert_eq!(switch.response_cache.len(), 1);
+
+        // Other sessions are not served from the cache.
+        assert_eq!(switch.cached_getpeers_response(&getpeers, 4321).unwrap().read_nodes().unwrap().len(), 2);
+
+        // The cache is bounded, dropping the oldest responses first.
+        for handle in 0..(MAX_CACHED_RESPONSES as u32) {
+            switch.cached_getpeers_response(&getpeers, 10000 + handle).unwrap();
+        }
+        assert_eq!(switch.response_cache.len(), MAX_CACHED_RESPONSES);
+        assert_eq!(switch.response_cache_order.len(), MAX_CACHED_RESPONSES);
+        assert!(!switch.response_cache.contains_key(&("gp".to_owned(), 1234)));
+        assert_eq!(switch.cached_getpeers_response(&getpeers, 1234).unwrap().read_nodes().unwrap().len(), 2);
+
+        // Expired responses are dropped.
+        switch.config.response_cache_ttl = Some(Duration::from_secs(0));
+        assert_eq!(switch.cached_getpeers_response(&getpeers, 1234).unwrap().read_nodes().unwrap().len(), 2);
+        assert_eq!(switch.response_cache.len(), 1);
+
+        // Without a TTL, responses are always built again.
+        switch.config.response_cache_ttl = None;
+        assert_eq!(switch.cached_getpeers_response(&getpeers, 1234).unwrap().read_nodes().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn sessions() {
+        let mut switch = new_switch_core();
+        assert_eq!(switch.sessions().count(), 0);
+
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let router_keys = gen_keypair();
+        connect_router(&mut switch, &router_keys, &ping.raw);
+        let sessions = switch.sessions().collect::<Vec<_>>();
+        assert_eq!(sessions.len(), 1);
+        let (handle, path, pk) = sessions[0];
+        assert!(switch.inner_conns.contains_key(&handle));
+        // The router is behind interface 0b011.
+        assert_eq!(*path, [0, 0, 0, 0, 0, 0, 0, 0b011]);
+        assert_eq!(pk, &router_keys.0);
+    }
+
+    #[test]
+    fn from_keys() {
+        fcp_cryptoauth::init();
+        let sk = "ac3e53b518e68449692b0b2f2926ef2fdc1eac5b9dbd10a48114263b8c8ed12e";
+        let pk = "2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0.k";
+        let other_pk = "2j1xz5k5y1xwz7kcczc4565jurhp8bbz1lqfu9kljw36p3nmb050.k";
+
+        let switch = SwitchCore::from_keys(sk, pk, vec![], HashMap::new()).unwrap();
+        assert_eq!(switch.my_pk, PublicKey::from_base32(pk.as_bytes()).unwrap());
+
+        assert_eq!(SwitchCore::from_keys("ac3e53", pk, vec![], HashMap::new()).err(), Some(KeyError::InvalidSecretKey));
+        assert_eq!(SwitchCore::from_keys(&sk.replace("a", "z"), pk, vec![], HashMap::new()).err(), Some(KeyError::InvalidSecretKey));
+        assert_eq!(SwitchCore::from_keys(sk, "2wrpv8p4", vec![], HashMap::new()).err(), Some(KeyError::InvalidPublicKey));
+        assert_eq!(SwitchCore::from_keys(sk, "2wrpv8p4.k", vec![], HashMap::new()).err(), Some(KeyError::InvalidPublicKey));
+        assert_eq!(SwitchCore::from_keys(sk, "", vec![], HashMap::new()).err(), Some(KeyError::InvalidPublicKey));
+        assert_eq!(SwitchCore::from_keys(sk, other_pk, vec![], HashMap::new()).err(), Some(KeyError::KeyMismatch));
+    }
+
+    #[test]
+    fn sequential_handle_allocator() {
+        let mut allocator = HandleAllocator::Sequential(1);
+        assert_eq!(allocator.next_handle(), 1);
+        assert_eq!(allocator.next_handle(), 2);
+        assert_eq!(allocator.next_handle(), 3);
+
+        // Handles 0 to 3 are reserved, and handles in use are skipped.
+        let mut switch = new_switch_core();
+        switch.set_handle_allocator(HandleAllocator::Sequential(0));
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        switch.set_handle_allocator(HandleAllocator::Sequential(5));
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        let mut handles = switch.sessions().map(|(handle, _, _)| handle).collect::<Vec<_>>();
+        handles.sort();
+        assert_eq!(handles, vec![4, 5, 6]);
+
+        // So is the control packet marker.
+        switch.set_handle_allocator(HandleAllocator::Sequential(0xffffffff));
+        connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        let mut handles = switch.sessions().map(|(handle, _, _)| handle).collect::<Vec<_>>();
+        handles.sort();
+        assert_eq!(handles, vec![4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn removed_session_not_announced() {
+        let mut switch = new_switch_core();
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let router_keys = gen_keypair();
+        connect_router(&mut switch, &router_keys, &ping.raw);
+        let (handle, _, _) = switch.sessions().next().unwrap();
+
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .finalize();
+        let nodes = switch.getpeers_response(&getpeers, 1234).unwrap().read_nodes().unwrap();
+        assert!(nodes.iter().any(|node| &node.public_key[..] == &(router_keys.0).0[..]));
+
+        assert!(switch.remove_session(handle));
+        assert!(!switch.remove_session(handle));
+        assert_eq!(switch.sessions().count(), 0);
+        let nodes = switch.getpeers_response(&getpeers, 1234).unwrap().read_nodes().unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert!(!nodes.iter().any(|node| &node.public_key[..] == &(router_keys.0).0[..]));
+    }
+
+    #[test]
+    fn label_to() {
+        let mut switch = new_switch_core();
+        // A router behind interface 0b011, knowing a node behind its own
+        // interface 0b101.
+        let (their_pk, _) = gen_keypair();
+        let inner_conn = Wrapper::new_outgoing_connection(switch.my_pk, switch.my_sk.clone(), their_pk, Credentials::None, None, (), None);
+        switch.inner_conns.insert(1234, ([0, 0, 0, 0, 0, 0, 0, 0b001_011], inner_conn));
+        let node = NodeData { public_key: [42; 32], path: [0, 0, 0, 0, 0, 0, 0, 0b001_101], version: 18 };
+
+        let label = switch.label_to(1234, &node).unwrap();
+        assert_eq!(label, [0, 0, 0, 0, 0, 0, 0, 0b001_101_011]);
+        let scheme = EncodingScheme::from_iter(vec![ENCODING_FORM].iter());
+        assert_eq!(::operation::hops_to_self(&label, &scheme), 2);
+
+        assert_eq!(switch.label_to(5678, &node), None);
+        let far_node = NodeData { path: label_from_u64(0b1 << 58), ..node };
+        assert_eq!(switch.label_to(1234, &far_node), None);
+    }
+
+    #[test]
+    fn prepare_label_for() {
+        let mut switch = new_switch_core();
+        let variable = EncodingScheme::from_iter(vec![
+            EncodingSchemeForm { prefix: 0b01, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b10, bit_count: 5, prefix_length: 2 },
+        ].iter());
+        let fixed = EncodingScheme::from_iter(vec![ENCODING_FORM].iter());
+
+        // Two routers, announcing different schemes.
+        for &(txid, ref scheme) in [(b"variable", &variable), (b"fixed___", &fixed)].iter() {
+            let ping = RoutePacketBuilder::new(18, txid.to_vec())
+                    .route_query(RouteQuery::Ping)
+                    .encoding_scheme((*scheme).clone())
+                    .encoding_index(0)
+                    .finalize();
+            let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+            connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        }
+        let variable_peer = *switch.peer_schemes.iter().find(|&(_, s)| *s == variable).unwrap().0;
+        let fixed_peer = *switch.peer_schemes.iter().find(|&(_, s)| *s == fixed).unwrap().0;
+
+        // Our interface 5, then interface 6 of the router's switch, then
+        // self. Only the director of the router's switch is converted.
+        let label = label_from_u64(0b001_110_101);
+        assert_eq!(switch.prepare_label_for(&label, variable_peer), Ok(label_from_u64(0b001_110_1_101)));
+        assert_eq!(switch.prepare_label_for(&label, fixed_peer), Ok(label));
+        assert_eq!(switch.prepare_label_for(&label, 42), Ok(label)); // Unknown scheme
+
+        // Our interface 5, then self: nothing to convert.
+        let label = label_from_u64(0b001_101);
+        assert_eq!(switch.prepare_label_for(&label, variable_peer), Ok(label));
+    }
+
+    #[test]
+    fn self_label_is_self_route() {
+        let scheme = EncodingScheme::from_iter(vec![ENCODING_FORM].iter());
+        assert!(is_self_route(&SELF_LABEL, &scheme));
+        assert_eq!(SELF_LABEL, [0, 0, 0, 0, 0, 0, 0, 0b001]);
+        let (_, decision) = ::operation::switch(&SELF_LABEL, ENCODING_FORM.bit_count, &0b011);
+        assert_eq!(decision, RoutingDecision::SelfInterface(0b001));
+        assert_eq!(label_from_u64(SELF_INTERFACE as u64), SELF_LABEL);
+    }
+
+    #[test]
+    fn replies_sent_from_self_interface() {
+        let mut switch = new_switch_core();
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        let mut out = Vec::new();
+        switch.send(packet, 0b011, &mut out);
+        let &(iface_id, ref pong) = out.iter().find(|&&(_, ref p)| match p.payload() {
+            Ok(SwitchPayload::Control(ControlPacket::Pong { .. })) => true,
+            _ => false,
+        }).unwrap();
+        assert_eq!(iface_id, 0b011);
+        // The return path starts with the (reversed) self interface.
+        let return_director = pong.label()[0] >> (8 - ENCODING_FORM.bit_count);
+        assert_eq!(return_director as u64, reverse_director(SELF_INTERFACE as Director, ENCODING_FORM.bit_count));
+    }
+
+    #[test]
+    fn getpeers_without_peers() {
+        let mut switch = new_switch_core();
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .finalize();
+        let response = switch.getpeers_response(&getpeers, 1234).unwrap();
+        let response = RoutePacket::decode(&response.encode()).unwrap();
+        assert_eq!(response.transaction_id, b"gp txid".to_vec());
+        let nodes = response.read_nodes().unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(&nodes[0].public_key[..], &switch.my_pk.0[..]);
+        assert_eq!(nodes[0].path, SELF_LABEL);
+
+        // Same thing through an inner session, the querying router
+        // being my only peer.
+        let getpeers = DataPacket::new(1, &DataPayload::RoutePacket(getpeers));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &getpeers.raw);
+        let response = open_route_packet(&mut router, &out);
+        assert_eq!(response.read_nodes().unwrap(), nodes);
+    }
+
+    /// Session which does not encrypt anything.
+    struct PassthroughSession {
+        their_pk: PublicKey,
+    }
+
+    impl Session for PassthroughSession {
+        fn wrap_message(&mut self, msg: &[u8]) -> Vec<Vec<u8>> {
+            vec![msg.to_vec()]
+        }
+        fn unwrap_message(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>, AuthFailure> {
+            Ok(vec![packet])
+        }
+        fn upkeep(&mut self) -> Vec<Vec<u8>> {
+            Vec::new()
+        }
+        fn peer_session_handle(&self) -> Option<u32> {
+            None
+        }
+        fn their_pk(&self) -> &PublicKey {
+            &self.their_pk
+        }
+    }
+
+    #[test]
+    fn mock_session() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let (their_pk, _) = gen_keypair();
+        let addr = "[::1]:1001".parse().unwrap();
+        let interface = Interface { id: 0b011, ca_session: Box::new(PassthroughSession { their_pk: their_pk }), addr: addr };
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![interface], my_pk, my_sk, HashMap::new(), config);
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        let mut datagrams = switch.on_outer_ca_message(addr, packet.raw);
+        assert_eq!(datagrams.len(), 1);
+        let (to, datagram) = datagrams.remove(0);
+        assert_eq!(to, addr);
+        let pong = SwitchPacket::parse(datagram).unwrap();
+        // Sent through interface 0b011, from the self interface.
+        assert_eq!(pong.label(), [0b100_00000, 0, 0, 0, 0, 0, 0, 0]);
+        match pong.payload() {
+            Ok(SwitchPayload::Control(ControlPacket::Pong { opaque_data, .. })) => assert_eq!(opaque_data, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn forward_with_interface_map() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let addr1 = "[::1]:1001".parse().unwrap();
+        let addr2 = "[::1]:1002".parse().unwrap();
+        let interfaces = vec![
+            Interface { id: 0b011, ca_session: Box::new(PassthroughSession { their_pk: gen_keypair().0 }), addr: addr1 },
+            Interface { id: 0b101, ca_session: Box::new(PassthroughSession { their_pk: gen_keypair().0 }), addr: addr2 },
+        ];
+        let mut switch = SwitchCore::new(interfaces, my_pk, my_sk, HashMap::new());
+        let map = switch.interface_map();
+        assert_eq!(map.addr(0b011), Some(addr1));
+
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001_101], SwitchPayload::CryptoAuthData(1234, vec![42]));
+        let (iface_id, packet) = map.forward(packet, 0b011).unwrap();
+        assert_eq!(iface_id, 0b101);
+        // Same as the slow path
+        let mut expected = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001_101], SwitchPayload::CryptoAuthData(1234, vec![42]));
+        expected.switch(3, &0b110).unwrap();
+        assert_eq!(packet.raw, expected.raw);
+        // The switch encrypts it for the peer.
+        assert_eq!(switch.wrap_for_interfaces(vec![(iface_id, packet)]), vec![(addr2, expected.raw.clone())]);
+
+        // The live interfaces change; the snapshot does not.
+        switch.interfaces.clear();
+        assert_eq!(map.addr(0b011), Some(addr1));
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001_101], SwitchPayload::CryptoAuthData(1234, vec![42]));
+        let (iface_id, packet) = map.forward(packet, 0b011).unwrap();
+        // It can no longer be sent.
+        assert!(switch.wrap_for_interfaces(vec![(iface_id, packet)]).is_empty());
+        assert_eq!(switch.dropped_packets(), 1);
+
+        // Left to the switch: self interface, loop route, unknown
+        // interface, exhausted label.
+        for &(label, label_shift) in [(0b0001, 0), (0b0001_011, 0), (0b0001_110, 0), (0b0001_101, 62)].iter() {
+            let mut packet = SwitchPacket::new(&label_from_u64(label), SwitchPayload::CryptoAuthData(1234, vec![42]));
+            packet.set_label_shift(label_shift);
+            let packet = map.forward(packet, 0b011).unwrap_err();
+            assert_eq!(packet.label(), label_from_u64(label));
+        }
+    }
+
+    /// Session which cannot send anything until its first upkeep, as if
+    /// it were completing a handshake.
+    struct DelayedSession {
+        their_pk: PublicKey,
+        ready: bool,
+    }
+
+    impl Session for DelayedSession {
+        fn wrap_message(&mut self, msg: &[u8]) -> Vec<Vec<u8>> {
+            if self.ready { vec![msg.to_vec()] } else { Vec::new() }
+        }
+        fn unwrap_message(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>, AuthFailure> {
+            Ok(vec![packet])
+        }
+        fn upkeep(&mut self) -> Vec<Vec<u8>> {
+            self.ready = true;
+            Vec::new()
+        }
+        fn peer_session_handle(&self) -> Option<u32> {
+            None
+        }
+        fn their_pk(&self) -> &PublicKey {
+            &self.their_pk
+        }
+    }
+
+    #[test]
+    fn empty_wrap_is_retried() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let (their_pk, _) = gen_keypair();
+        let addr = "[::1]:1001".parse().unwrap();
+        let interface = Interface { id: 0b011, ca_session: Box::new(DelayedSession { their_pk: their_pk, ready: false }), addr: addr };
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![interface], my_pk, my_sk, HashMap::new(), config);
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(ping));
+        assert!(switch.on_outer_ca_message(addr, packet.raw).is_empty());
+
+        let mut datagrams = switch.upkeep();
+        assert_eq!(datagrams.len(), 1);
+        let (to, datagram) = datagrams.remove(0);
+        assert_eq!(to, addr);
+        match SwitchPacket::parse(datagram).unwrap().payload() {
+            Ok(SwitchPayload::Control(ControlPacket::Pong { opaque_data, .. })) => assert_eq!(opaque_data, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+
+        // Sent only once.
+        assert!(switch.upkeep().is_empty());
+    }
+
+    #[test]
+    fn pending_packets_are_bounded() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let (their_pk, _) = gen_keypair();
+        let addr = "[::1]:1001".parse().unwrap();
+        let interface = Interface { id: 0b011, ca_session: Box::new(DelayedSession { their_pk: their_pk, ready: false }), addr: addr };
+        let config = SwitchConfig { proactive_discovery: false, pending_packet_ttl: Duration::from_millis(1), ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![interface], my_pk, my_sk, HashMap::new(), config);
+
+        // Packets beyond the limit are dropped right away.
+        let packets = (0..(MAX_PENDING_PACKETS+1)).map(|_| {
+            (0b011, SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthData(1234, vec![42])))
+        }).collect();
+        assert!(switch.wrap_for_interfaces(packets).is_empty());
+        assert_eq!(switch.pending_packets.len(), MAX_PENDING_PACKETS);
+        assert_eq!(switch.dropped_packets, 1);
+
+        // The other ones are dropped once they expire, even though the
+        // session is now ready.
+        ::std::thread::sleep(Duration::from_millis(2));
+        assert!(switch.upkeep().is_empty());
+        assert!(switch.pending_packets.is_empty());
+        assert_eq!(switch.dropped_packets, 1 + MAX_PENDING_PACKETS as u64);
+    }
+
+    #[test]
+    fn control_error() {
+        let mut switch = new_switch_core();
+        let dead_path = label_from_u64(0b001_110_101);
+        let alive_path = label_from_u64(0b001_111_101);
+        switch.import_nodes(&[
+            NodeData { public_key: [1; 32], path: dead_path, version: 18 },
+            NodeData { public_key: [2; 32], path: alive_path, version: 18 },
+        ]);
+
+        // The error comes from the second switch of the path, so the
+        // first director of the cause was replaced with the return path.
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let mut cause = SwitchPacket::new(&dead_path, SwitchPayload::Control(ping));
+        cause.switch(3, &0b110).unwrap();
+        assert!(cause.label() != dead_path);
+        let error = ControlPacket::Error { type_: ErrorType::Undeliverable, cause: cause.raw };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(error));
+        let mut out = Vec::new();
+        switch.send(packet, 0b011, &mut out);
+
+        assert!(out.is_empty());
+        assert!(switch.is_route_dead(&dead_path));
+        assert!(!switch.is_route_dead(&alive_path));
+        let nodes = switch.export_nodes();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].path, alive_path);
+    }
+
+    #[test]
+    fn dead_routes_are_bounded() {
+        let mut switch = new_switch_core();
+        for i in 0..(MAX_DEAD_ROUTES as u64 + 1) {
+            let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+            let cause = SwitchPacket::new(&label_from_u64(0b1000 | i << 4), SwitchPayload::Control(ping));
+            let error = ControlPacket::Error { type_: ErrorType::Undeliverable, cause: cause.raw };
+            let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(error));
+            switch.send(packet, 0b011, &mut Vec::new());
+        }
+        assert_eq!(switch.dead_routes.len(), MAX_DEAD_ROUTES);
+        assert!(!switch.is_route_dead(&label_from_u64(0b1000)));
+        assert!(switch.is_route_dead(&label_from_u64(0b1000 | (MAX_DEAD_ROUTES as u64) << 4)));
+    }
+
+    #[test]
+    fn payload_mtu() {
+        let switch = new_switch_core();
+        // Outer CryptoAuth, switch header, session handle, inner CryptoAuth,
+        // data header.
+        assert_eq!(switch.payload_mtu(), 1452 - 20 - 12 - 4 - 20 - 4);
+        assert_eq!(switch.payload_mtu(), 1392);
+    }
+
+    #[test]
+    fn peer_version() {
+        let mut switch = new_switch_core();
+        let router_keys = gen_keypair();
+        let ping = RoutePacketBuilder::new(17, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        connect_router(&mut switch, &router_keys, &ping.raw);
+
+        let nodes = switch.export_nodes();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].version, 17);
+
+        // Announced to other routers
+        let getpeers = RoutePacketBuilder::new(18, b"gp txid".to_vec())
+                .route_query(RouteQuery::GetPeers)
+                .finalize();
+        let response = switch.getpeers_response(&getpeers, 1234).unwrap();
+        let nodes = response.read_nodes().unwrap();
+        let node = nodes.iter().find(|node| &node.public_key[..] == &(router_keys.0).0[..]).unwrap();
+        assert_eq!(node.version, 17);
+    }
+
+    #[test]
+    fn peer_version_too_large() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+        let router_keys = gen_keypair();
+        let ping = RoutePacketBuilder::new(300, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        connect_router(&mut switch, &router_keys, &ping.raw);
+        assert_eq!(switch.export_nodes()[0].version, MAX_NODE_VERSION);
+
+        // Other routers can still get it.
+        let getpeers = RoutePacket::get_peers(18, b"gp txid".to_vec(), vec![0; 16]);
+        let getpeers = DataPacket::new(1, &DataPayload::RoutePacket(getpeers));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &getpeers.raw);
+        let nodes = open_route_packet(&mut router, &out).read_nodes().unwrap();
+        let node = nodes.iter().find(|node| &node.public_key[..] == &(router_keys.0).0[..]).unwrap();
+        assert_eq!(node.version, MAX_NODE_VERSION);
+        assert_eq!(switch.dropped_packets, 0);
+    }
+
+    #[test]
+    fn configured_version_too_large() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, version: 256, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+        let getpeers = RoutePacket::get_peers(18, b"gp txid".to_vec(), vec![0; 16]);
+        let getpeers = DataPacket::new(1, &DataPayload::RoutePacket(getpeers));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &getpeers.raw);
+        let response = open_route_packet(&mut router, &out);
+        assert_eq!(response.protocol_version, 256);
+        assert_eq!(response.read_nodes().unwrap()[0].version, MAX_NODE_VERSION);
+        assert_eq!(switch.dropped_packets, 0);
+    }
+
+    #[test]
+    fn drop_unknown_handle() {
+        let mut switch = new_switch_core();
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthData(1234, vec![1, 2, 3, 4]));
+        let mut out = Vec::new();
+        switch.send(packet, 0b011, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(switch.dropped_packets(), 1);
+    }
+
+    #[test]
+    fn drop_bad_inner_messages() {
+        fcp_cryptoauth::init();
+        let (my_pk, my_sk) = gen_keypair();
+        let config = SwitchConfig { proactive_discovery: false, ..SwitchConfig::default() };
+        let mut switch = SwitchCore::with_config(vec![], my_pk, my_sk, HashMap::new(), config);
+
+        // Unknown content type
+        let (_, out) = connect_router(&mut switch, &gen_keypair(), &[0x10, 0, 0x12, 0x34]);
+        assert!(out.is_empty());
+        assert_eq!(switch.dropped_packets(), 1);
+
+        // Not a hello
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::CryptoAuthHandshake(vec![0; 150]));
+        switch.send(packet, 0b011, &mut Vec::new());
+        assert_eq!(switch.dropped_packets(), 2);
+
+        // Replayed data packet
+        let ping = RoutePacket::ping(18, b"ping txid".to_vec());
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        open_route_packet(&mut router, &out);
+        let message = router.wrap_message_immediately(&ping.raw).remove(0);
+        let packet = SwitchPacket::from_ca_message(&[0, 0, 0, 0, 0, 0, 0, 0b001], router.peer_session_handle(), message).unwrap();
+        let mut out = Vec::new();
+        switch.send(SwitchPacket::parse(packet.raw.clone()).unwrap(), 0b011, &mut out);
+        assert_eq!(out.len(), 1);
+        switch.send(packet, 0b011, &mut Vec::new());
+        assert_eq!(switch.dropped_packets(), 3);
+
+        // Key ping
+        let key_ping = ControlPacket::KeyPing { version: 18, opaque_data: vec![1, 2, 3, 4], key: vec![0; 32] };
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], SwitchPayload::Control(key_ping));
+        switch.send(packet, 0b011, &mut Vec::new());
+        assert_eq!(switch.dropped_packets(), 4);
+    }
+
+    #[test]
+    fn drop_bad_outer_messages() {
+        fcp_cryptoauth::init();
+        let credentials = Credentials::LoginPassword { login: b"foo".to_vec(), password: b"bar".to_vec() };
+        let mut allowed_peers = HashMap::new();
+        allowed_peers.insert(credentials.clone(), "my peer".to_owned());
+        let (my_pk, my_sk) = gen_keypair();
+        let mut switch = SwitchCore::new(vec![], my_pk, my_sk, allowed_peers);
+
+        // Garbage from an unknown address
+        assert!(switch.on_outer_ca_message("[::1]:1000".parse().unwrap(), vec![0; 150]).is_empty());
+        assert_eq!(switch.dropped_packets(), 1);
+        assert!(switch.interfaces.is_empty());
+
+        // Only eight directors are available.
+        for port in 1001..1009 {
+            connect_peer(&mut switch, &credentials, &gen_keypair(), SocketAddr::new("::1".parse().unwrap(), port));
+        }
+        assert_eq!(switch.interfaces.len(), 8);
+        let peer_keys = gen_keypair();
+        let mut peer = Wrapper::new_outgoing_connection(peer_keys.0, peer_keys.1, switch.my_pk, credentials, None, "switch".to_owned(), None);
+        let hello = peer.wrap_message_immediately(&[0; 16]).remove(0);
+        assert!(switch.on_outer_ca_message("[::1]:1009".parse().unwrap(), hello).is_empty());
+        assert_eq!(switch.dropped_packets(), 2);
+        assert_eq!(switch.interfaces.len(), 8);
+    }
+
+    #[test]
+    fn drop_looping_route_packet() {
+        let mut switch = new_switch_core();
+        let my_pk = key_bytes(&switch.my_pk);
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .as_path(&[[2u8; 32], my_pk])
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let (_router, out) = connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        // Only (random) switch pings, no route packet.
+        assert!(out.iter().all(|&(_, ref packet)| packet.packet_type() == PacketType::Control));
+        assert_eq!(switch.dropped_packets(), 1);
+    }
+
+    #[test]
+    fn reply_route_ping() {
+        let mut switch = new_switch_core();
+        let ping = RoutePacketBuilder::new(18, b"ping txid".to_vec())
+                .route_query(RouteQuery::Ping)
+                .finalize();
+        let ping = DataPacket::new(1, &DataPayload::RoutePacket(ping));
+        let (mut router, out) = connect_router(&mut switch, &gen_keypair(), &ping.raw);
+        let pong = open_route_packet(&mut router, &out);
+        assert_eq!(pong.query, None);
+        assert_eq!(pong.transaction_id, b"ping txid".to_vec());
+    }
+}
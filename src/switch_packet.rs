@@ -2,11 +2,16 @@
 //! used by the Switch, as defined by
 //! https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#in-memory-representation
 
+use std::fmt;
+use std::error;
+
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
 
 use operation::{switch, reverse_label, Director, RoutingDecision, Label};
-use control::ControlPacket;
+use operation::{WILDCARD_LABEL, first_form};
+use control::{ControlPacket, ControlError};
+use encoding_scheme::EncodingScheme;
 
 #[derive(Debug)]
 pub enum Payload {
@@ -15,18 +20,249 @@ pub enum Payload {
     CryptoAuthData(u32, Vec<u8>), // First argument is the session handle
 }
 
+impl Payload {
+    /// Returns the type of the packets carrying this payload. Packet
+    /// constructors (`SwitchPacket::new`, `SwitchPacket::new_reply`, ...)
+    /// infer it this way, so callers never pass it.
+    pub fn packet_type(&self) -> PacketType {
+        match *self {
+            Payload::Control(_) => PacketType::Control,
+            Payload::CryptoAuthHandshake(_) => PacketType::CryptoAuthHandshake,
+            Payload::CryptoAuthData(_, _) => PacketType::CryptoAuthData,
+        }
+    }
+}
+
+/// Kind of content carried by a switch packet. It is not stored in
+/// the switch header, but inferred from the four bytes following it.
+///
+/// In particular, control packets are not told apart by their label:
+/// the label only tells which switch they are for (eg.
+/// `0000.0000.0000.0001`, the self interface, for the switch receiving
+/// them), and the same labels are used for CryptoAuth packets.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum PacketType {
+    /// Control packet, marked with `0xffffffff`.
+    Control,
+    /// CryptoAuth handshake packet, starting with a session state
+    /// lower than 4.
+    CryptoAuthHandshake,
+    /// CryptoAuth data packet, starting with the session handle.
+    CryptoAuthData,
+}
+
+/// Minimum length of a switch packet: the header, and the four bytes
+/// telling its type.
+pub const MIN_SWITCH_PACKET_LENGTH: usize = 12 + 4;
+
+/// Version of the switch header written by `SwitchPacket::new`, the one
+/// used by cjdns.
+pub const SWITCH_HEADER_VERSION: u8 = 1;
+
+/// Typed view of a message received from a direct peer, once
+/// decrypted by the outer CryptoAuth session. See `parse_outer`.
+#[derive(Debug)]
+pub enum OuterEvent {
+    /// The message is too short to be a switch packet.
+    Malformed(Vec<u8>),
+    /// A switch packet carrying a control packet (ping, error, ...).
+    Control(SwitchPacket),
+    /// A switch packet carrying an end-to-end (aka inner) CryptoAuth
+    /// handshake packet.
+    Handshake(SwitchPacket),
+    /// A switch packet carrying an end-to-end CryptoAuth data packet.
+    SwitchData(SwitchPacket),
+}
+
+/// Classifies a message received from a direct peer, according to the
+/// type of its content, without routing it nor decoding the content.
+pub fn parse_outer(bytes: Vec<u8>) -> OuterEvent {
+    if bytes.len() < MIN_SWITCH_PACKET_LENGTH {
+        return OuterEvent::Malformed(bytes)
+    }
+    let packet = SwitchPacket::parse(bytes).unwrap(); // Long enough
+    match packet.packet_type() {
+        PacketType::Control => OuterEvent::Control(packet),
+        PacketType::CryptoAuthHandshake => OuterEvent::Handshake(packet),
+        PacketType::CryptoAuthData => OuterEvent::SwitchData(packet),
+    }
+}
+
+/// Splits a batch of packets into control packets and CryptoAuth
+/// (handshake and data) packets, keeping their order.
+pub fn partition_packets(packets: Vec<SwitchPacket>) -> (Vec<SwitchPacket>, Vec<SwitchPacket>) {
+    packets.into_iter().partition(|packet| packet.packet_type() == PacketType::Control)
+}
+
+/// An error returned when a buffer cannot be read as a switch packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SwitchParseError {
+    /// The buffer is shorter than a switch header; the argument is
+    /// its length.
+    HeaderTooShort(usize),
+    /// The buffer contains a switch header, but its payload is
+    /// truncated; the argument is the length of the buffer.
+    PayloadTruncated(usize),
+}
+
+impl fmt::Display for SwitchParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SwitchParseError::HeaderTooShort(length) => write!(f, "header too short ({} bytes)", length),
+            SwitchParseError::PayloadTruncated(length) => write!(f, "payload truncated ({} bytes)", length),
+        }
+    }
+}
+
+impl error::Error for SwitchParseError {}
+
+/// An error returned by `SwitchPacket::payload`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PayloadError {
+    /// The packet is too short to tell the type of its content; the
+    /// argument is its length.
+    Truncated(usize),
+    /// The packet is marked as a control packet, but its content
+    /// cannot be decoded as one.
+    BadControlPacket(ControlError),
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PayloadError::Truncated(length) => write!(f, "truncated ({} bytes)", length),
+            PayloadError::BadControlPacket(ref e) => write!(f, "bad control packet: {}", e),
+        }
+    }
+}
+
+impl error::Error for PayloadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PayloadError::Truncated(_) => None,
+            PayloadError::BadControlPacket(ref e) => Some(e),
+        }
+    }
+}
+
+/// Largest congestion value, which fits in 7 bits.
+const MAX_CONGESTION: u8 = 0b1111111;
+
+/// Flags of a switch header, stored in its `congestAndSuppressErrors`
+/// byte. Whether the packet is a control packet is not one of them: it
+/// is told by the four bytes after the header (see `PacketType`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SwitchHeaderFlags {
+    /// 7-bit congestion value, set by switches forwarding the packet on
+    /// a congested link.
+    pub congestion: u8,
+    /// If set, switches must not send control errors about the packet.
+    pub suppress_errors: bool,
+}
+
+impl SwitchHeaderFlags {
+    /// Decodes the flags from the `congestAndSuppressErrors` byte.
+    pub fn from_byte(byte: u8) -> SwitchHeaderFlags {
+        SwitchHeaderFlags {
+            congestion: byte >> 1,
+            suppress_errors: byte & 0b00000001 == 1,
+        }
+    }
+
+    /// Encodes the flags to the `congestAndSuppressErrors` byte.
+    /// Panics if the congestion does not fit in 7 bits.
+    pub fn to_byte(&self) -> u8 {
+        assert!(self.congestion <= MAX_CONGESTION);
+        (self.congestion << 1) | (self.suppress_errors as u8)
+    }
+
+    /// Returns whether a switch marked the packet as experiencing
+    /// congestion.
+    pub fn is_congested(&self) -> bool {
+        self.congestion != 0
+    }
+}
 
+/// An error returned by `SwitchPacket::switch`, which leaves the packet
+/// unchanged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SwitchError {
+    /// The label was already shifted by so many bits (as counted by the
+    /// label shift of the header) that consuming an other director would
+    /// shift it by more than 63 bits: only padding is left.
+    LabelExhausted,
+    /// The reversed origin interface does not fit in the director
+    /// length.
+    OriginTooLong(Director),
+}
+
+impl fmt::Display for SwitchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SwitchError::LabelExhausted => write!(f, "label exhausted"),
+            SwitchError::OriginTooLong(director) => write!(f, "origin interface {:b} longer than the director", director),
+        }
+    }
+}
+
+impl error::Error for SwitchError {}
 
 #[derive(Debug)]
 pub struct SwitchPacket {
+    /// The raw packet, header included. Its length is not checked when
+    /// it is set directly; methods of `SwitchPacket` panic if it is
+    /// shorter than `MIN_SWITCH_PACKET_LENGTH`. Use `SwitchPacket::parse`
+    /// to check it.
+    ///
+    /// Writing to it does not change `original_label`.
     pub raw: Vec<u8>,
+    /// The label the packet had when it was parsed or built, before
+    /// `switch` replaced it. See `SwitchPacket::original_label`.
+    original_label: Label,
 }
 
 impl SwitchPacket {
+    /// Returns a packet from its raw representation, checking it
+    /// contains a switch header and the four bytes telling the type of
+    /// its content.
+    pub fn parse(raw: Vec<u8>) -> Result<SwitchPacket, SwitchParseError> {
+        if raw.len() < 12 {
+            Err(SwitchParseError::HeaderTooShort(raw.len()))
+        }
+        else if raw.len() < MIN_SWITCH_PACKET_LENGTH {
+            Err(SwitchParseError::PayloadTruncated(raw.len()))
+        }
+        else {
+            Ok(SwitchPacket::from_raw(raw))
+        }
+    }
+
+    /// Returns a packet from its raw representation, without checking
+    /// its length, like setting `raw` directly. Its label is recorded
+    /// as `original_label`. Panics if it is shorter than the label.
+    ///
+    /// Packets cannot be built with a `SwitchPacket { raw: ... }`
+    /// literal anymore, use this instead.
+    pub fn from_raw(raw: Vec<u8>) -> SwitchPacket {
+        let mut original_label = [0u8; 8];
+        original_label.copy_from_slice(&raw[0..8]);
+        SwitchPacket { raw: raw, original_label: original_label }
+    }
+
     /// Returns a new packet, constructed from its route and its payload.
+    ///
+    /// The header is fully initialized: no congestion, errors are not
+    /// suppressed, the version is `SWITCH_HEADER_VERSION`, the label
+    /// shift is 0 (no hop was consumed yet), and the penalty is 0.
+    /// The payload is prefixed with the marker of its `PacketType`
+    /// (`0xffffffff` for control packets, the session handle for
+    /// CryptoAuth data packets).
     pub fn new(route_label: &[u8; 8], payload: Payload) -> SwitchPacket {
         let mut raw = vec![0u8; 12];
         raw[0..8].copy_from_slice(route_label);
+        raw[8] = SwitchHeaderFlags::default().to_byte(); // congestion and suppressErrors
+        raw[9] = SWITCH_HEADER_VERSION << 6; // version and label shift
+        BigEndian::write_u16(&mut raw[10..12], 0); // penalty
         match payload {
             Payload::Control(msg) => {
                 raw.append(&mut vec![0xff, 0xff, 0xff, 0xff]);
@@ -47,14 +283,37 @@ impl SwitchPacket {
                 raw.append(&mut msg);
             },
         }
-        SwitchPacket { raw: raw }
+        SwitchPacket { raw: raw, original_label: *route_label }
+    }
+
+    /// Returns a new packet sending a message of an end-to-end (aka inner)
+    /// CryptoAuth session (as returned by its `wrap_message`) on the
+    /// given route.
+    ///
+    /// Handshake messages are sent as they are; data messages are
+    /// prefixed with `peer_handle`, the handle the other end assigned to
+    /// the session, so they cannot be mistaken for control packets even
+    /// if they start with `0xffffffff`.
+    ///
+    /// Returns None if the message is too short to be a CryptoAuth message
+    /// (less than 4 bytes), or if it is a data message and `peer_handle`
+    /// is None.
+    pub fn from_ca_message(route_label: &Label, peer_handle: Option<u32>, ca_message: Vec<u8>) -> Option<SwitchPacket> {
+        if ca_message.len() < 4 {
+            return None
+        }
+        let session_state = BigEndian::read_u32(&ca_message[0..4]);
+        if session_state < 4 {
+            Some(SwitchPacket::new(route_label, Payload::CryptoAuthHandshake(ca_message)))
+        }
+        else {
+            peer_handle.map(|peer_handle| SwitchPacket::new(route_label, Payload::CryptoAuthData(peer_handle, ca_message)))
+        }
     }
 
     /// Returns a new packet, constructed as a reply of a received one.
     pub fn new_reply(received: &SwitchPacket, payload: Payload) -> SwitchPacket {
-        let mut response_label = received.label();
-        reverse_label(&mut response_label);
-        SwitchPacket::new(&response_label, payload)
+        SwitchPacket::new(&received.reply_label(), payload)
     }
 
     /// Returns the address label of the packet.
@@ -64,34 +323,175 @@ impl SwitchPacket {
         label
     }
 
+    /// Returns the label the packet had when it was parsed or built, ie.
+    /// its path before it was routed: `switch` replaces the directors it
+    /// consumes in `label` with the return path, so `label` is only the
+    /// original one until then.
+    ///
+    /// Unaffected by `set_label` and by writing to `raw`.
+    pub fn original_label(&self) -> Label {
+        self.original_label
+    }
+
+    /// Replaces the address label of the packet, keeping the rest of
+    /// the header and the payload.
+    pub fn set_label(&mut self, label: &Label) {
+        self.debug_check_length();
+        self.raw[0..8].copy_from_slice(label);
+    }
+
+    /// Returns a packet with the same header (label, congestion,
+    /// suppressErrors, version, label shift, penalty), but a new
+    /// payload, prefixed with the marker of its `PacketType`.
+    pub fn with_payload(self, payload: Payload) -> SwitchPacket {
+        self.debug_check_length();
+        let mut packet = SwitchPacket::new(&self.label(), payload);
+        packet.raw[8..12].copy_from_slice(&self.raw[8..12]);
+        packet.original_label = self.original_label;
+        packet.debug_check_length();
+        packet
+    }
+
+    /// Checks, in debug builds, that `raw` is long enough to be parsed
+    /// by `SwitchPacket::parse`. Setters write the header in place, so
+    /// they can only get a malformed packet if `raw` was set directly.
+    fn debug_check_length(&self) {
+        debug_assert!(self.raw.len() >= MIN_SWITCH_PACKET_LENGTH, "Switch packet too short: {} bytes", self.raw.len());
+    }
+
+    /// Returns the label as four 16-bit groups, most significant first,
+    /// as cjdns prints paths (eg. `0000.0000.0000.0013`).
+    pub fn label_groups(&self) -> [u16; 4] {
+        let mut groups = [0u16; 4];
+        for (i, group) in groups.iter_mut().enumerate() {
+            *group = BigEndian::read_u16(&self.raw[2*i..2*i+2]);
+        }
+        groups
+    }
+
+    /// Returns the flags of the header, stored in its
+    /// `congestAndSuppressErrors` byte.
+    pub fn flags(&self) -> SwitchHeaderFlags {
+        SwitchHeaderFlags::from_byte(self.raw[8])
+    }
+
+    /// Writes the flags of the header. Panics if the congestion does not
+    /// fit in 7 bits.
+    pub fn set_flags(&mut self, flags: SwitchHeaderFlags) {
+        self.raw[8] = flags.to_byte();
+    }
+
+    /// Returns the 7-bit congestion value, stored in the high bits of
+    /// `congestAndSuppressErrors`.
+    pub fn congestion(&self) -> u8 {
+        self.flags().congestion
+    }
+
+    #[deprecated(note = "Renamed to `congestion`.")]
     pub fn congest(&self) -> u8 {
-        self.raw[8] >> 1
+        self.congestion()
     }
 
+    /// Sets the congestion value, without changing the suppressErrors
+    /// bit. Panics if it does not fit in 7 bits.
+    pub fn set_congestion(&mut self, congestion: u8) {
+        let flags = SwitchHeaderFlags { congestion: congestion, ..self.flags() };
+        self.set_flags(flags);
+    }
+
+    /// Returns whether a switch on the path marked this packet as
+    /// experiencing congestion.
+    pub fn is_congested(&self) -> bool {
+        self.flags().is_congested()
+    }
+
+    /// Marks this packet as experiencing congestion (eg. because the
+    /// queue of the interface it is sent to is full), by setting all
+    /// the congestion bits. Does not change the suppressErrors bit.
+    pub fn mark_congested(&mut self) {
+        self.set_congestion(MAX_CONGESTION);
+    }
+
+    /// Returns the suppressErrors flag, stored in the low bit of
+    /// `congestAndSuppressErrors`: if set, switches must not send
+    /// control errors about this packet.
     pub fn suppress_errors(&self) -> bool {
-        self.raw[8] & 0b00000001 == 1
+        self.flags().suppress_errors
     }
 
+    /// Sets the suppressErrors flag, without changing the congestion.
+    pub fn set_suppress_errors(&mut self, suppress_errors: bool) {
+        let flags = SwitchHeaderFlags { suppress_errors: suppress_errors, ..self.flags() };
+        self.set_flags(flags);
+    }
+
+    /// Returns the 2-bit version of the switch header, stored in the
+    /// high bits of `versionAndLabelShift`.
     pub fn version(&self) -> u8 {
         self.raw[9] >> 6
     }
 
+    /// Returns the 6-bit label shift, stored in the low bits of
+    /// `versionAndLabelShift`.
     pub fn label_shift(&self) -> u8 {
         self.raw[9] & 0b00111111
     }
 
-    pub fn penalty(&self) -> [u8; 2] {
-        let mut a = [0u8; 2];
-        a.copy_from_slice(&self.raw[10..12]);
-        a
+    /// Sets the version of the switch header, without changing the
+    /// label shift. Panics if it does not fit in 2 bits.
+    pub fn set_version(&mut self, version: u8) {
+        assert!(version < 0b100);
+        self.raw[9] = (version << 6) | self.label_shift();
+    }
+
+    /// Sets the label shift, without changing the version. Panics if it
+    /// does not fit in 6 bits.
+    pub fn set_label_shift(&mut self, label_shift: u8) {
+        assert!(label_shift < 0b1000000);
+        self.raw[9] = (self.raw[9] & 0b11000000) | label_shift;
+    }
+
+    /// Returns the penalty, accumulated by the switches on the path of
+    /// the packet (eg. when forwarding it on a congested link).
+    pub fn penalty(&self) -> u16 {
+        BigEndian::read_u16(&self.raw[10..12])
+    }
+
+    pub fn set_penalty(&mut self, penalty: u16) {
+        BigEndian::write_u16(&mut self.raw[10..12], penalty);
+    }
+
+    /// Returns the type of the content of the packet.
+    pub fn packet_type(&self) -> PacketType {
+        match BigEndian::read_u32(&self.raw[12..16]) {
+            0xffffffff => PacketType::Control,
+            0 | 1 | 2 | 3 => PacketType::CryptoAuthHandshake,
+            _ => PacketType::CryptoAuthData,
+        }
+    }
+
+    /// Returns the session handle of a CryptoAuth data packet, without
+    /// decoding its payload, or None for other packet types.
+    pub fn session_handle(&self) -> Option<u32> {
+        match self.packet_type() {
+            PacketType::CryptoAuthData => Some(BigEndian::read_u32(&self.raw[12..16])),
+            PacketType::Control | PacketType::CryptoAuthHandshake => None,
+        }
     }
 
     /// Returns a reference to the content of the packet.
-    pub fn payload(&self) -> Option<Payload> {
+    ///
+    /// Packets marked with `0xffffffff` are always read as (unencrypted)
+    /// control packets, never as CryptoAuth data, so an error is
+    /// returned if the control packet cannot be decoded.
+    pub fn payload(&self) -> Result<Payload, PayloadError> {
+        if self.raw.len() < MIN_SWITCH_PACKET_LENGTH {
+            return Err(PayloadError::Truncated(self.raw.len()))
+        }
         match BigEndian::read_u32(&self.raw[12..16]) {
-            0xffffffff => ControlPacket::decode(&self.raw[16..].to_vec()).map(Payload::Control),
-            0 | 1 | 2 | 3 => Some(Payload::CryptoAuthHandshake(self.raw[12..].to_vec())),
-            handle => Some(Payload::CryptoAuthData(handle, self.raw[16..].to_vec())),
+            0xffffffff => ControlPacket::decode(&self.raw[16..].to_vec()).map(Payload::Control).map_err(PayloadError::BadControlPacket),
+            0 | 1 | 2 | 3 => Ok(Payload::CryptoAuthHandshake(self.raw[12..].to_vec())),
+            handle => Ok(Payload::CryptoAuthData(handle, self.raw[16..].to_vec())),
         }
     }
 
@@ -100,40 +500,111 @@ impl SwitchPacket {
     /// Using the Director Length of this switch, determines what interface
     /// the packet will go next, update the label of the packet to take that
     /// switching into account (pops the interface from the path, and puts it
-    /// on the reverse path), then returns the interface.
+    /// on the reverse path), adds the director length to the label shift,
+    /// then returns the interface.
     ///
     /// See the doc of `fcp_switching::operation::switch` for more details.
-    pub fn switch(&mut self, director_length: u8, reversed_origin_iface: &Director) -> RoutingDecision {
+    pub fn switch(&mut self, director_length: u8, reversed_origin_iface: &Director) -> Result<RoutingDecision, SwitchError> {
+        if director_length >= 64 || *reversed_origin_iface >= 0b1u64 << director_length {
+            return Err(SwitchError::OriginTooLong(*reversed_origin_iface))
+        }
+        let label_shift = self.label_shift() as u32 + director_length as u32;
+        if label_shift > 63 {
+            return Err(SwitchError::LabelExhausted)
+        }
         let (new_label, decision) = switch(&self.label(), director_length, reversed_origin_iface);
         self.raw[0..8].copy_from_slice(&new_label);
-        decision
+        self.set_label_shift(label_shift as u8);
+        Ok(decision)
+    }
+
+    /// Returns the next director of the label (its `bits` least
+    /// significant bits), ie. the one `switch` would consume with
+    /// `bits` as director length, without modifying the packet.
+    pub fn next_director(&self, bits: u8) -> Director {
+        assert!(bits > 0 && bits < 64);
+        BigEndian::read_u64(&self.raw[0..8]) & ((0b1u64 << bits) - 1)
+    }
+
+    /// Returns whether `switch`, with `bits` as director length, would
+    /// route this packet to the self interface, without modifying the
+    /// packet. The self interface is the director ending with `0001`
+    /// (not an all-zero director); the wildcard label is also delivered
+    /// to it.
+    pub fn is_next_hop_self(&self, bits: u8) -> bool {
+        match switch(&self.label(), bits, &0) {
+            (_, RoutingDecision::SelfInterface(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns whether `switch` would route this packet to the self
+    /// interface of a switch using the given encoding scheme, without
+    /// modifying the packet.
+    ///
+    /// Returns false if no form of the scheme matches the first director
+    /// of the label.
+    pub fn is_for_self(&self, scheme: &EncodingScheme) -> bool {
+        let label = self.label();
+        if label == WILDCARD_LABEL {
+            return true
+        }
+        let forms = scheme.into_iter().collect::<Vec<_>>();
+        first_form(BigEndian::read_u64(&label), &forms)
+                .map_or(false, |form| self.is_next_hop_self(form.prefix_length + form.bit_count))
+    }
+
+    /// Returns the label a reply to this packet should be sent with,
+    /// ie. the return path accumulated by the switches this packet
+    /// went through.
+    ///
+    /// Used by `new_reply`; this is useful to originate a reply which is
+    /// not built with `new_reply`.
+    pub fn reply_label(&self) -> Label {
+        let mut label = self.label();
+        reverse_label(&mut label);
+        label
     }
 
     /// Inverses the path and the return path.
     pub fn reverse_label(&mut self) {
         // TODO: do this in-place/no-copy.
-        let mut label = [0u8; 8];
-        reverse_label(&mut label);
+        let label = self.reply_label();
         self.raw[0..8].copy_from_slice(&label);
     }
 
 }
 
+impl fmt::Display for SwitchPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.raw.len() < 12 {
+            return write!(f, "SwitchPacket(<malformed>)")
+        }
+        let groups = self.label_groups();
+        try!(write!(f, "SwitchPacket(label={:04x}.{:04x}.{:04x}.{:04x}, version={}, shift={}, congestion={}, penalty={}, payload=",
+                groups[0], groups[1], groups[2], groups[3], self.version(), self.label_shift(), self.congestion(), self.penalty()));
+        match self.payload() {
+            Ok(payload) => write!(f, "{:?})", payload),
+            Err(_) => write!(f, "<malformed>)"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use hex::FromHex;
     use super::*;
-    use super::super::operation::RoutingDecision;
+    use super::super::operation::{RoutingDecision, label_from_u64};
     use super::super::control::ControlPacket;
 
     #[test]
     fn switch_and_reply() {
-        let mut received = SwitchPacket { raw: Vec::from_hex("800000000000000100440000ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap() };
-        let decision = received.switch(4, &0b1100);
+        let mut received = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap()).unwrap();
+        let decision = received.switch(4, &0b1100).unwrap();
         let opaque_data = match decision {
             RoutingDecision::SelfInterface(_) => {
                 match received.payload() {
-                    Some(Payload::Control(ControlPacket::Ping { opaque_data, .. })) => {
+                    Ok(Payload::Control(ControlPacket::Ping { opaque_data, .. })) => {
                         opaque_data
                     },
                     _ => panic!("parsed as non-Ping."),
@@ -143,8 +614,433 @@ mod test {
         };
         let control_response = ControlPacket::Pong { version: 17, opaque_data: opaque_data };
         let mut response = SwitchPacket::new_reply(&received, Payload::Control(control_response));
-        let decision = response.switch(4, &0b1000);
+        let decision = response.switch(4, &0b1000).unwrap();
         assert_eq!(decision, RoutingDecision::Forward(0b0011));
-        assert_eq!(response.raw, Vec::from_hex("800000000000000100000000ffffffff33b000049d74e35b00000011467c6febbde26264a38cd12e").unwrap());
+        assert_eq!(response.raw, Vec::from_hex("800000000000000100440000ffffffff33b000049d74e35b00000011467c6febbde26264a38cd12e").unwrap());
+    }
+
+    #[test]
+    fn reply_label() {
+        let mut received = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap()).unwrap();
+        received.switch(4, &0b1100).unwrap();
+
+        let mut expected = received.label();
+        reverse_label(&mut expected);
+        assert_eq!(received.reply_label(), expected);
+        assert_eq!(received.reply_label(), [0, 0, 0, 0, 0, 0, 0, 0b1_0011]);
+
+        let reply = SwitchPacket::new_reply(&received, Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(reply.label(), expected);
+
+        received.reverse_label();
+        assert_eq!(received.label(), expected);
+    }
+
+    #[test]
+    fn original_label() {
+        let mut received = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap()).unwrap();
+        let original = [0x80, 0, 0, 0, 0, 0, 0, 0b1];
+        assert_eq!(received.original_label(), original);
+        assert_eq!(received.label(), original);
+        received.switch(4, &0b1100).unwrap();
+        assert_eq!(received.original_label(), original);
+        assert!(received.label() != original);
+        received.set_label(&[0, 0, 0, 0, 0, 0, 0, 0b1_0011]);
+        assert_eq!(received.original_label(), original);
+
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_101_011];
+        let mut packet = SwitchPacket::new(&label, Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(packet.switch(3, &0b100).unwrap(), RoutingDecision::Forward(0b011));
+        assert_eq!(packet.original_label(), label);
+        assert!(packet.label() != label);
+        let packet = packet.with_payload(Payload::CryptoAuthData(1234, vec![1, 2, 3]));
+        assert_eq!(packet.original_label(), label);
+    }
+
+    #[test]
+    fn from_ca_message() {
+        use super::super::data_packet::{DataPacket, Payload as DataPayload};
+        use super::super::route_packet::RoutePacketBuilder;
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_0011];
+
+        let route_packet = RoutePacketBuilder::new(18, b"txid".to_vec()).finalize();
+        let data_packet = DataPacket::new(1, &DataPayload::RoutePacket(route_packet));
+        // Stands for the data packet wrapped in an established session,
+        // which starts with a nonce greater than 3.
+        let mut ca_message = vec![0, 0, 0, 4];
+        ca_message.extend_from_slice(&data_packet.raw);
+        let packet = SwitchPacket::from_ca_message(&label, Some(1234), ca_message.clone()).unwrap();
+        assert_eq!(packet.label(), label);
+        assert_eq!(packet.session_handle(), Some(1234));
+        match packet.payload() {
+            Ok(Payload::CryptoAuthData(1234, msg)) => assert_eq!(msg, ca_message),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+
+        let packet = SwitchPacket::from_ca_message(&label, None, vec![0, 0, 0, 1, 42]).unwrap();
+        assert_eq!(packet.packet_type(), PacketType::CryptoAuthHandshake);
+        match packet.payload() {
+            Ok(Payload::CryptoAuthHandshake(msg)) => assert_eq!(msg, vec![0, 0, 0, 1, 42]),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+
+        // Too short, or no handle to send a data message with.
+        assert!(SwitchPacket::from_ca_message(&label, Some(1234), vec![0, 0, 4]).is_none());
+        assert!(SwitchPacket::from_ca_message(&label, None, ca_message).is_none());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(SwitchPacket::parse(vec![]).unwrap_err(), SwitchParseError::HeaderTooShort(0));
+        assert_eq!(SwitchPacket::parse(vec![0; 4]).unwrap_err(), SwitchParseError::HeaderTooShort(4));
+        assert_eq!(SwitchPacket::parse(vec![0; 11]).unwrap_err(), SwitchParseError::HeaderTooShort(11));
+        assert_eq!(SwitchPacket::parse(vec![0; 12]).unwrap_err(), SwitchParseError::PayloadTruncated(12));
+        assert_eq!(SwitchPacket::parse(vec![0; 15]).unwrap_err(), SwitchParseError::PayloadTruncated(15));
+
+        let raw = Vec::from_hex("800000000000000100440000ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap();
+        let packet = SwitchPacket::parse(raw.clone()).unwrap();
+        assert_eq!(packet.raw, raw);
+        assert_eq!(packet.packet_type(), PacketType::Control);
+
+        let packet = SwitchPacket::from_raw(vec![0; 14]);
+        assert_eq!(packet.payload().unwrap_err(), PayloadError::Truncated(14));
+    }
+
+    #[test]
+    fn parse_outer() {
+        use super::parse_outer;
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_0011];
+
+        match parse_outer(vec![0; 15]) {
+            OuterEvent::Malformed(bytes) => assert_eq!(bytes, vec![0; 15]),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let raw = SwitchPacket::new(&label, Payload::Control(ping)).raw;
+        match parse_outer(raw.clone()) {
+            OuterEvent::Control(packet) => assert_eq!(packet.raw, raw),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+
+        let raw = SwitchPacket::new(&label, Payload::CryptoAuthHandshake(vec![0, 0, 0, 1, 42])).raw;
+        match parse_outer(raw.clone()) {
+            OuterEvent::Handshake(packet) => assert_eq!(packet.raw, raw),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+
+        let raw = SwitchPacket::new(&label, Payload::CryptoAuthData(1234, vec![42])).raw;
+        match parse_outer(raw.clone()) {
+            OuterEvent::SwitchData(packet) => assert_eq!(packet.raw, raw),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn control_marker() {
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_0011];
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let packet = SwitchPacket::new(&label, Payload::Control(ping.clone()));
+        assert_eq!(&packet.raw[12..16], &[0xff, 0xff, 0xff, 0xff]);
+        match packet.payload() {
+            Ok(Payload::Control(p)) => assert_eq!(p, ping),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+
+        // Not a valid control packet, but still not a data packet.
+        let mut raw = label.to_vec();
+        raw.extend_from_slice(&[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+        let packet = SwitchPacket::parse(raw).unwrap();
+        assert_eq!(packet.packet_type(), PacketType::Control);
+        assert_eq!(packet.session_handle(), None);
+        match packet.payload() {
+            Err(PayloadError::BadControlPacket(_)) => (),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+
+        // A data message starting with 0xffffffff is prefixed with
+        // the session handle.
+        let packet = SwitchPacket::from_ca_message(&label, Some(1234), vec![0xff, 0xff, 0xff, 0xff, 42]).unwrap();
+        assert_eq!(packet.packet_type(), PacketType::CryptoAuthData);
+        match packet.payload() {
+            Ok(Payload::CryptoAuthData(1234, msg)) => assert_eq!(msg, vec![0xff, 0xff, 0xff, 0xff, 42]),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn partition_packets() {
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_0011];
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let pong = ControlPacket::Pong { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let batch = vec![
+            SwitchPacket::new(&label, Payload::Control(ping)),
+            SwitchPacket::new(&label, Payload::CryptoAuthData(1234, vec![1])),
+            SwitchPacket::new(&label, Payload::CryptoAuthHandshake(vec![0, 0, 0, 1, 2])),
+            SwitchPacket::new(&label, Payload::Control(pong)),
+            SwitchPacket::new(&label, Payload::CryptoAuthData(1234, vec![3])),
+        ];
+        let raws = batch.iter().map(|packet| packet.raw.clone()).collect::<Vec<_>>();
+        let (control, data) = super::partition_packets(batch);
+        assert_eq!(control.into_iter().map(|packet| packet.raw).collect::<Vec<_>>(), vec![raws[0].clone(), raws[3].clone()]);
+        assert_eq!(data.into_iter().map(|packet| packet.raw).collect::<Vec<_>>(), vec![raws[1].clone(), raws[2].clone(), raws[4].clone()]);
+    }
+
+    #[test]
+    fn display() {
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let mut packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0x13], Payload::Control(ping));
+        packet.set_penalty(5);
+        assert_eq!(format!("{}", packet), "SwitchPacket(label=0000.0000.0000.0013, version=1, shift=0, congestion=0, penalty=5, payload=Control(Ping { version: 18, opaque_data: [1, 2, 3, 4, 5, 6, 7, 8] }))");
+
+        packet.raw.truncate(22);
+        assert_eq!(format!("{}", packet), "SwitchPacket(label=0000.0000.0000.0013, version=1, shift=0, congestion=0, penalty=5, payload=<malformed>)");
+
+        packet.raw.truncate(4);
+        assert_eq!(format!("{}", packet), "SwitchPacket(<malformed>)");
+    }
+
+    #[test]
+    fn switch_errors() {
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_011];
+        let mut packet = SwitchPacket::new(&label, Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(packet.switch(3, &0b1000), Err(SwitchError::OriginTooLong(0b1000)));
+        assert_eq!(packet.label(), label);
+
+        // Only padding is left once 61 bits were consumed.
+        packet.set_label_shift(61);
+        assert_eq!(packet.switch(3, &0b110), Err(SwitchError::LabelExhausted));
+        assert_eq!(packet.label(), label);
+        assert_eq!(packet.label_shift(), 61);
+
+        packet.set_label_shift(60);
+        assert_eq!(packet.switch(3, &0b100), Ok(RoutingDecision::Forward(0b011)));
+        assert_eq!(packet.label_shift(), 63);
+    }
+
+    #[test]
+    fn label_groups() {
+        let packet = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff").unwrap()).unwrap();
+        assert_eq!(packet.label_groups(), [0x8000, 0x0000, 0x0000, 0x0001]);
+
+        let packet = SwitchPacket::new(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0], Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(packet.label_groups(), [0x1234, 0x5678, 0x9abc, 0xdef0]);
+    }
+
+    #[test]
+    fn congestion() {
+        let mut packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001], Payload::CryptoAuthData(1234, vec![]));
+        assert!(!packet.is_congested());
+        packet.mark_congested();
+        assert!(packet.is_congested());
+        assert_eq!(packet.congestion(), 0b1111111);
+        assert!(!packet.suppress_errors());
+
+        let mut packet = SwitchPacket::parse(Vec::from_hex("800000000000000101440000ffffffff").unwrap()).unwrap();
+        assert!(!packet.is_congested());
+        assert!(packet.suppress_errors());
+        packet.mark_congested();
+        assert!(packet.is_congested());
+        assert!(packet.suppress_errors());
+        assert_eq!(packet.raw[9..12], [0x44, 0, 0]);
+    }
+
+    #[test]
+    fn congestion_and_suppress_errors() {
+        let mut packet = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff").unwrap()).unwrap();
+        assert_eq!(packet.congestion(), 0);
+        assert!(!packet.suppress_errors());
+
+        packet.set_congestion(0b1010101);
+        assert_eq!(packet.raw[8], 0b1010101_0);
+        packet.set_suppress_errors(true);
+        assert_eq!(packet.raw[8], 0b1010101_1);
+        assert_eq!(packet.congestion(), 0b1010101);
+        assert!(packet.suppress_errors());
+        packet.set_congestion(0);
+        assert_eq!(packet.raw[8], 0b0000000_1);
+        assert!(!packet.is_congested());
+        packet.set_suppress_errors(false);
+        assert_eq!(packet.raw[8], 0);
+        // Other fields are untouched
+        assert_eq!(packet.raw, Vec::from_hex("800000000000000100440000ffffffff").unwrap());
+    }
+
+    #[test]
+    fn header_flags() {
+        let mut packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001_0011], Payload::CryptoAuthData(1234, vec![42]));
+        assert_eq!(packet.flags(), SwitchHeaderFlags::default());
+
+        for &(congestion, suppress_errors) in [(0, true), (42, false), (42, true), (MAX_CONGESTION, false)].iter() {
+            let flags = SwitchHeaderFlags { congestion: congestion, suppress_errors: suppress_errors };
+            assert_eq!(SwitchHeaderFlags::from_byte(flags.to_byte()), flags);
+            packet.set_flags(flags);
+            let parsed = SwitchPacket::parse(packet.raw.clone()).unwrap();
+            assert_eq!(parsed.flags(), flags);
+            assert_eq!(parsed.congestion(), congestion);
+            assert_eq!(parsed.suppress_errors(), suppress_errors);
+            assert_eq!(parsed.flags().is_congested(), congestion != 0);
+            // Nothing else changed
+            assert_eq!(parsed.label(), [0, 0, 0, 0, 0, 0, 0, 0b0001_0011]);
+            assert_eq!(parsed.version(), SWITCH_HEADER_VERSION);
+            assert_eq!(parsed.session_handle(), Some(1234));
+        }
+        assert_eq!(SwitchHeaderFlags::from_byte(0b1010101_1), SwitchHeaderFlags { congestion: 0b1010101, suppress_errors: true });
+    }
+
+    #[test]
+    fn penalty() {
+        let mut packet = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap()).unwrap();
+        assert_eq!(packet.penalty(), 0);
+        packet.set_penalty(0x1234);
+        assert_eq!(packet.penalty(), 0x1234);
+        assert_eq!(packet.raw, Vec::from_hex("800000000000000100441234ffffffff9986000309f9110200000011467c6febbde26264a38cd12e").unwrap());
+
+        let reply = SwitchPacket::new_reply(&packet, Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(reply.penalty(), 0);
+    }
+
+    #[test]
+    fn version_and_label_shift() {
+        // versionAndLabelShift is 0x44: version 1, label shift 4.
+        let mut packet = SwitchPacket::parse(Vec::from_hex("800000000000000100440000ffffffff").unwrap()).unwrap();
+        assert_eq!(packet.version(), 1);
+        assert_eq!(packet.label_shift(), 4);
+
+        packet.set_label_shift(63);
+        assert_eq!(packet.version(), 1);
+        assert_eq!(packet.label_shift(), 63);
+        packet.set_version(2);
+        assert_eq!(packet.version(), 2);
+        assert_eq!(packet.label_shift(), 63);
+        assert_eq!(packet.raw[9], 0b10_111111);
+
+        packet.set_version(1);
+        packet.set_label_shift(4);
+        assert_eq!(packet.raw, Vec::from_hex("800000000000000100440000ffffffff").unwrap());
+    }
+
+    #[test]
+    fn new_header() {
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_0011];
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4] };
+        let payload = Payload::Control(ping);
+        assert_eq!(payload.packet_type(), PacketType::Control);
+        let packet = SwitchPacket::new(&label, payload);
+        assert_eq!(packet.label(), label);
+        assert_eq!(packet.congestion(), 0);
+        assert!(!packet.suppress_errors());
+        assert_eq!(packet.version(), SWITCH_HEADER_VERSION);
+        assert_eq!(packet.label_shift(), 0);
+        assert_eq!(packet.penalty(), 0);
+        assert_eq!(packet.packet_type(), PacketType::Control);
+
+        let payload = Payload::CryptoAuthHandshake(vec![0, 0, 0, 1, 42]);
+        assert_eq!(payload.packet_type(), PacketType::CryptoAuthHandshake);
+        let packet = SwitchPacket::new(&label, payload);
+        assert_eq!(packet.penalty(), 0);
+        assert_eq!(packet.packet_type(), PacketType::CryptoAuthHandshake);
+
+        let payload = Payload::CryptoAuthData(1234, vec![42]);
+        assert_eq!(payload.packet_type(), PacketType::CryptoAuthData);
+        let packet = SwitchPacket::new(&label, payload);
+        assert_eq!(packet.penalty(), 0);
+        assert_eq!(packet.packet_type(), PacketType::CryptoAuthData);
+    }
+
+    #[test]
+    fn new_to_four_hop_label() {
+        // Through interfaces 0b011, 0b100, 0b110 and 0b101, then the
+        // self interface.
+        let label = label_from_u64(0b001_101_110_100_011);
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let raw = SwitchPacket::new(&label, Payload::Control(ping.clone())).raw;
+        assert_eq!(&raw[8..16], &[0, 0b01_000000, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+
+        let mut packet = SwitchPacket::parse(raw).unwrap();
+        assert_eq!(packet.label(), label);
+        match packet.payload() {
+            Ok(Payload::Control(p)) => assert_eq!(p, ping),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+        for &iface in [0b011, 0b100, 0b110, 0b101].iter() {
+            assert_eq!(packet.switch(3, &0b111), Ok(RoutingDecision::Forward(iface)));
+        }
+        match packet.switch(3, &0b111) {
+            Ok(RoutingDecision::SelfInterface(_)) => (),
+            d => panic!("Unexpected decision: {:?}", d),
+        }
+    }
+
+    #[test]
+    fn setters_round_trip() {
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let mut packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b001], Payload::Control(ping));
+        packet.set_label(&[0, 0, 0, 0, 0, 0, 0b1, 0b0001_0011]);
+        packet.set_congestion(42);
+        packet.set_suppress_errors(true);
+        packet.set_label_shift(7);
+        packet.set_penalty(0x1234);
+        let packet = packet.with_payload(Payload::CryptoAuthData(1234, vec![42; 10]));
+
+        let parsed = SwitchPacket::parse(packet.raw.clone()).unwrap();
+        assert_eq!(parsed.raw, packet.raw);
+        assert_eq!(parsed.label(), [0, 0, 0, 0, 0, 0, 0b1, 0b0001_0011]);
+        assert_eq!(parsed.congestion(), 42);
+        assert!(parsed.suppress_errors());
+        assert_eq!(parsed.version(), SWITCH_HEADER_VERSION);
+        assert_eq!(parsed.label_shift(), 7);
+        assert_eq!(parsed.penalty(), 0x1234);
+        match parsed.payload() {
+            Ok(Payload::CryptoAuthData(1234, msg)) => assert_eq!(msg, vec![42; 10]),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn session_handle() {
+        let label = [0, 0, 0, 0, 0, 0, 0, 0b0001_0011];
+
+        let ping = ControlPacket::Ping { version: 18, opaque_data: vec![1, 2, 3, 4] };
+        let packet = SwitchPacket::new(&label, Payload::Control(ping));
+        assert_eq!(packet.session_handle(), None);
+
+        let packet = SwitchPacket::new(&label, Payload::CryptoAuthHandshake(vec![0, 0, 0, 3, 42]));
+        assert_eq!(packet.session_handle(), None);
+
+        let packet = SwitchPacket::new(&label, Payload::CryptoAuthData(1234, vec![42]));
+        assert_eq!(packet.session_handle(), Some(1234));
+    }
+
+    #[test]
+    fn is_for_self() {
+        use std::iter::FromIterator;
+        use super::super::encoding_scheme::EncodingSchemeForm;
+        let scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
+
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001], Payload::CryptoAuthData(1234, vec![]));
+        assert!(packet.is_for_self(&scheme));
+
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001_011], Payload::CryptoAuthData(1234, vec![]));
+        assert!(!packet.is_for_self(&scheme));
+        // Not modified
+        assert_eq!(packet.label(), [0, 0, 0, 0, 0, 0, 0, 0b0001_011]);
+    }
+
+    #[test]
+    fn next_director() {
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001_011], Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(packet.next_director(3), 0b011);
+        assert_eq!(packet.next_director(4), 0b1011);
+        assert!(!packet.is_next_hop_self(3));
+        assert_eq!(packet.label(), [0, 0, 0, 0, 0, 0, 0, 0b0001_011]);
+
+        let packet = SwitchPacket::new(&[0, 0, 0, 0, 0, 0, 0, 0b0001], Payload::CryptoAuthData(1234, vec![]));
+        assert_eq!(packet.next_director(3), 0b001);
+        assert!(packet.is_next_hop_self(3));
+        assert!(packet.is_next_hop_self(4));
+        let packet = SwitchPacket::new(&WILDCARD_LABEL, Payload::CryptoAuthData(1234, vec![]));
+        assert!(packet.is_next_hop_self(3));
     }
 }